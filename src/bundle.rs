@@ -0,0 +1,484 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::cancel;
+use crate::config;
+use crate::encryption;
+use crate::logging;
+use crate::redact;
+use crate::session_manager;
+use crate::summary::{self, JobOutcome};
+use crate::upload;
+use crate::ssh_utils;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const SUMMARY_FILE: &str = "summary.txt";
+const SECRET_KEYS: [&str; 4] = ["password", "secret", "token", "auth"];
+
+/// File or directory produced by a collector.
+#[derive(Clone, Debug)]
+pub struct Collected {
+    pub path: String,
+    /// What the file was taken from, e.g. `journal kubelet.service`.
+    pub source: String,
+    /// Lines are counted only for text output.
+    pub text: bool,
+    /// Set when the item could not be collected, the path is empty then.
+    /// An item cut short keeps the path of its partial output.
+    pub error: Option<String>,
+}
+
+impl Collected {
+    pub fn failed(source: String, err: &anyhow::Error) -> Self {
+        Collected { path: String::new(), source, text: false, error: Some(format!("{:#}", err)) }
+    }
+}
+
+/// Keeps a failed item of a collector which gathers many of them, so the rest still
+/// gets collected. A broken session is passed on to be retried.
+pub fn keep_failed(item: Result<Collected>, source: String) -> Result<Collected> {
+    match keep_partial(item, source.clone()) {
+        Err(err) if !session_manager::is_broken_session(&err) => Ok(Collected::failed(source, &err)),
+        item => item,
+    }
+}
+
+/// Keeps the partial output of an item cut short by cancellation or a timeout.
+pub fn keep_partial(item: Result<Collected>, source: String) -> Result<Collected> {
+    match item {
+        Err(err) => match err.downcast_ref::<cancel::PartialOutput>() {
+            Some(partial) => Ok(Collected {
+                path: partial.path.clone(),
+                source,
+                text: false,
+                error: Some(err.to_string()),
+            }),
+            None => Err(err),
+        },
+        item => item,
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactStatus {
+    Ok,
+    /// Cut short by cancellation or a timeout.
+    Partial,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ArtifactRecord {
+    /// Relative to the run directory.
+    pub path: Option<String>,
+    pub source: String,
+    pub node: Option<String>,
+    pub size: u64,
+    pub lines: Option<u64>,
+    pub sha256: Option<String>,
+    pub status: ArtifactStatus,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimeWindow {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub harvester_version: &'static str,
+    pub created_at: DateTime<Utc>,
+    pub time_window: TimeWindow,
+    pub nodes: Vec<String>,
+    pub loki_services: ServiceNames,
+    /// Effective config with secrets masked.
+    pub config: Value,
+    pub artifacts: Vec<ArtifactRecord>,
+    pub jobs: Vec<JobOutcome>,
+    /// Present when redaction was enabled.
+    pub redaction: Option<redact::RedactionReport>,
+    /// Where the bundle, or its volume manifest when it is split, is uploaded to after packing.
+    pub upload: Option<upload::UploadRecord>,
+}
+
+/// Loki services of the run, e.g. `loki app=ptaf-core`.
+#[derive(Debug, Default, Serialize)]
+pub struct ServiceNames {
+    /// After patterns of the config were resolved, or discovered.
+    pub collected: Vec<String>,
+    /// Of the config, without streams in the time window.
+    pub without_streams: Vec<String>,
+}
+
+/// Output of a single run: `<dir>/harvester_<timestamp>/` packed into
+/// `<dir>/harvester_<timestamp>.tar.gz` (or `.tar.zst`) with a manifest inside.
+pub struct Bundle {
+    name: String,
+    output: config::OutputConfig,
+    /// The bundle is encrypted for them, when there are any.
+    recipients: Vec<age::x25519::Recipient>,
+    run_dir: PathBuf,
+    artifacts: Mutex<Vec<ArtifactRecord>>,
+    jobs: Mutex<Vec<JobOutcome>>,
+}
+
+impl Bundle {
+
+    /// `recipients` are parsed from `output.recipients` up front, so a bad key fails
+    /// the run before anything is collected.
+    pub fn create(
+        output: &config::OutputConfig,
+        recipients: Vec<age::x25519::Recipient>,
+        started: DateTime<Utc>,
+    ) -> Result<Self> {
+        let name = format!("harvester_{}", started.format("%Y%m%d_%H%M%S"));
+        let run_dir = Path::new(&output.dir).join(&name);
+        fs::create_dir_all(&run_dir)?;
+        Ok(Bundle {
+            name,
+            output: output.clone(),
+            recipients,
+            run_dir,
+            artifacts: Mutex::new(vec![]),
+            jobs: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn run_dir(&self) -> &str {
+        self.run_dir.to_str().unwrap_or(".")
+    }
+
+    /// Records every file of the collected paths, directories are walked.
+    /// Returns the new records.
+    pub fn add_collected(&self, node: Option<&str>, collected: &[Collected]) -> Vec<ArtifactRecord> {
+        let mut records = vec![];
+        for item in collected {
+            if let Some(error) = &item.error {
+                if item.path.is_empty() {
+                    records.push(failed_record(node, &item.source, error.clone()));
+                    continue;
+                }
+            }
+            for file in walk(Path::new(&item.path)) {
+                let mut record = self.file_record(&file, node, item);
+                if record.status == ArtifactStatus::Ok && item.error.is_some() {
+                    record.status = ArtifactStatus::Partial;
+                    record.error = item.error.clone();
+                }
+                records.push(record);
+            }
+        }
+        self.artifacts.lock().unwrap().extend(records.clone());
+        records
+    }
+
+    pub fn add_failed(&self, node: Option<&str>, source: &str, err: &anyhow::Error) {
+        self.artifacts.lock().unwrap().push(failed_record(node, source, format!("{:#}", err)));
+    }
+
+    pub fn add_job(&self, outcome: JobOutcome) {
+        self.jobs.lock().unwrap().push(outcome);
+    }
+
+    /// Outcomes in the order jobs finished.
+    pub fn jobs(&self) -> Vec<JobOutcome> {
+        self.jobs.lock().unwrap().clone()
+    }
+
+    fn file_record(&self, file: &Path, node: Option<&str>, item: &Collected) -> ArtifactRecord {
+        let path = file
+            .strip_prefix(&self.run_dir)
+            .unwrap_or(file)
+            .to_string_lossy()
+            .to_string();
+        let mut record = ArtifactRecord {
+            path: Some(path),
+            source: item.source.clone(),
+            node: node.map(|x| x.to_string()),
+            size: 0,
+            lines: None,
+            sha256: None,
+            status: ArtifactStatus::Ok,
+            error: None,
+        };
+        let stats = file_stats(file, item.text);
+        match stats {
+            Ok((size, lines, sha256)) => {
+                record.size = size;
+                record.lines = lines;
+                record.sha256 = Some(sha256);
+            },
+            Err(err) => {
+                record.status = ArtifactStatus::Failed;
+                record.error = Some(format!("{:#}", err));
+            },
+        }
+        record
+    }
+
+    /// Writes the manifest and packs the run directory. Returns the bundle path.
+    pub fn finish(
+        &self,
+        config: &config::Config,
+        nodes: &[String],
+        loki_services: ServiceNames,
+        redactor: Option<&redact::Redactor>,
+        upload: Option<upload::UploadRecord>,
+    ) -> Result<String> {
+        let packed = self.close_log(redactor).and_then(|_| {
+            // The report counts the pseudonyms of the log as well.
+            self.write_manifest(config, nodes, loki_services, redactor.map(|x| x.report()), upload)?;
+            match self.recipients.is_empty() {
+                true => self.pack(),
+                false => self.pack_encrypted(),
+            }
+        });
+        // Plain copies of the collected data must not stay on disk, also when packing failed.
+        if !self.recipients.is_empty() {
+            fs::remove_dir_all(&self.run_dir)?;
+        }
+        packed
+    }
+
+    /// Later events would change the log while it is being packed. It holds addresses
+    /// of nodes, commands and their stderr, so it is redacted like the artifacts.
+    fn close_log(&self, redactor: Option<&redact::Redactor>) -> Result<()> {
+        logging::close_file();
+        let path = self.run_dir.join(logging::LOG_FILE);
+        match redactor {
+            Some(redactor) if path.exists() => redactor.redact_file(&path),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_manifest(
+        &self,
+        config: &config::Config,
+        nodes: &[String],
+        loki_services: ServiceNames,
+        redaction: Option<redact::RedactionReport>,
+        upload: Option<upload::UploadRecord>,
+    ) -> Result<()> {
+        let mut masked = serde_json::to_value(config)?;
+        mask_secrets(&mut masked);
+        let manifest = Manifest {
+            harvester_version: env!("CARGO_PKG_VERSION"),
+            created_at: Utc::now(),
+            time_window: TimeWindow { from: config.param.loki.log_from, to: config.param.loki.log_to },
+            nodes: nodes.to_vec(),
+            loki_services,
+            config: masked,
+            artifacts: self.artifacts.lock().unwrap().clone(),
+            jobs: self.jobs(),
+            redaction,
+            upload,
+        };
+        fs::write(self.run_dir.join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest)?)?;
+        fs::write(self.run_dir.join(SUMMARY_FILE), summary::table(&manifest.jobs))?;
+        Ok(())
+    }
+
+    /// Name of the bundle `finish` creates.
+    pub fn file_name(&self) -> String {
+        match !self.recipients.is_empty() {
+            true => format!("{}.{}.{}", self.name, self.extension(), encryption::EXTENSION),
+            false => format!("{}.{}", self.name, self.extension()),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.output.format {
+            config::BundleFormat::TarGz => "tar.gz",
+            config::BundleFormat::TarZst => "tar.zst",
+        }
+    }
+
+    fn pack(&self) -> Result<String> {
+        let bundle_path = Path::new(&self.output.dir).join(self.file_name());
+        self.pack_to(fs::File::create(&bundle_path)?)?;
+        Ok(bundle_path.to_string_lossy().to_string())
+    }
+
+    /// The archive is encrypted while it is written, so no plain bundle is created.
+    fn pack_encrypted(&self) -> Result<String> {
+        let bundle_path = Path::new(&self.output.dir).join(self.file_name());
+        let writer = encryption::encrypt(&self.recipients, fs::File::create(&bundle_path)?)?;
+        self.pack_to(writer)?.finish()?;
+        Ok(bundle_path.to_string_lossy().to_string())
+    }
+
+    fn pack_to<W: Write>(&self, output: W) -> Result<W> {
+        let output = match self.output.format {
+            config::BundleFormat::TarGz => {
+                let encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+                let mut builder = tar::Builder::new(encoder);
+                builder.append_dir_all(&self.name, &self.run_dir)?;
+                builder.into_inner()?.finish()?
+            },
+            config::BundleFormat::TarZst => {
+                let encoder = zstd::stream::write::Encoder::new(output, 0)?;
+                let mut builder = tar::Builder::new(encoder);
+                builder.append_dir_all(&self.name, &self.run_dir)?;
+                builder.into_inner()?.finish()?
+            },
+        };
+        Ok(output)
+    }
+}
+
+fn failed_record(node: Option<&str>, source: &str, error: String) -> ArtifactRecord {
+    ArtifactRecord {
+        path: None,
+        source: source.to_string(),
+        node: node.map(|x| x.to_string()),
+        size: 0,
+        lines: None,
+        sha256: None,
+        status: ArtifactStatus::Failed,
+        error: Some(error),
+    }
+}
+
+/// Size, line count and sha256 of a local file.
+fn file_stats(file: &Path, text: bool) -> Result<(u64, Option<u64>, String)> {
+    let size = fs::metadata(file)?.len();
+    let lines = if text {
+        let mut count = 0;
+        let mut reader = BufReader::new(fs::File::open(file)?);
+        let mut buf = vec![];
+        while reader.read_until(b'\n', &mut buf)? > 0 {
+            count += 1;
+            buf.clear();
+        }
+        Some(count)
+    } else {
+        None
+    };
+    let sha256 = ssh_utils::sha256_file(&file.to_string_lossy())?;
+    Ok((size, lines, sha256))
+}
+
+fn walk(path: &Path) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return vec![path.to_path_buf()];
+    }
+    let mut result = vec![];
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            result.extend(walk(&entry.path()));
+        }
+    }
+    result.sort();
+    result
+}
+
+/// Replaces non empty values of keys which look like secrets with `***`.
+pub fn mask_secrets(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, val) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if SECRET_KEYS.iter().any(|x| key.contains(x)) && !val.is_null() {
+                    *val = Value::String("***".to_string());
+                } else {
+                    mask_secrets(val);
+                }
+            }
+        },
+        Value::Array(items) => items.iter_mut().for_each(mask_secrets),
+        _ => {},
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+
+    #[test]
+    fn test_mask_secrets() {
+        let mut cfg = config::Config::from_string(constants::DEFAULT_CONFIG).unwrap();
+        cfg.param.loki.password = "admin".to_string();
+        cfg.param.ssh.password = Some("root".to_string());
+        let mut value = serde_json::to_value(&cfg).unwrap();
+        mask_secrets(&mut value);
+
+        assert_eq!(value["param"]["loki"]["password"], "***");
+        assert_eq!(value["param"]["ssh"]["password"], "***");
+        assert_eq!(value["param"]["nodes"]["sudo_password"], Value::Null);
+        assert_eq!(value["param"]["ssh"]["login"], cfg.param.ssh.login.as_str());
+    }
+
+    #[test]
+    fn test_bundle() {
+        // `finish` closes the log file.
+        let _lock = logging::TEST_FILE_LOCK.lock().unwrap_or_else(|x| x.into_inner());
+        let output = config::OutputConfig {
+            dir: std::env::temp_dir().join("harvester_test_bundle").to_string_lossy().to_string(),
+            format: config::BundleFormat::TarGz,
+            recipients: None,
+            volume_size_mb: 0,
+        };
+        let bundle = Bundle::create(&output, vec![], Utc::now()).unwrap();
+        let log = format!("{}/svc.log", bundle.run_dir());
+        fs::write(&log, "a\nb\n").unwrap();
+        bundle.add_collected(None, &[Collected { path: log, source: "loki app=svc".to_string(), text: true, error: None }]);
+
+        let cfg = config::Config::from_string(constants::DEFAULT_CONFIG).unwrap();
+        let bundle_path = bundle.finish(&cfg, &[], ServiceNames::default(), None, None).unwrap();
+        assert!(Path::new(&bundle_path).exists());
+
+        let manifest: Value = serde_json::from_str(
+            &fs::read_to_string(Path::new(bundle.run_dir()).join(MANIFEST_FILE)).unwrap()
+        ).unwrap();
+        assert_eq!(manifest["artifacts"][0]["path"], "svc.log");
+        assert_eq!(manifest["artifacts"][0]["lines"], 2);
+        assert_eq!(manifest["artifacts"][0]["size"], 4);
+        fs::remove_dir_all(&output.dir).unwrap();
+    }
+
+    #[test]
+    fn test_bundle_log_redacted() {
+        let _lock = logging::TEST_FILE_LOCK.lock().unwrap_or_else(|x| x.into_inner());
+        let output = config::OutputConfig {
+            dir: std::env::temp_dir().join("harvester_test_bundle_log").to_string_lossy().to_string(),
+            format: config::BundleFormat::TarGz,
+            recipients: None,
+            volume_size_mb: 0,
+        };
+        let bundle = Bundle::create(&output, vec![], Utc::now()).unwrap();
+        logging::open_file(&Path::new(bundle.run_dir()).join(logging::LOG_FILE)).unwrap();
+        tracing::subscriber::with_default(
+            tracing_subscriber::fmt().json().with_writer(|| logging::FileWriter).finish(),
+            || tracing::warn!(node = "10.0.0.7:22013", "node is unreachable"),
+        );
+
+        let cfg = config::Config::from_string(constants::DEFAULT_CONFIG).unwrap();
+        let redactor = redact::Redactor::new(&cfg.param.redaction).unwrap();
+        let bundle_path = bundle.finish(&cfg, &[], ServiceNames::default(), Some(&redactor), None).unwrap();
+
+        let file = fs::File::open(&bundle_path).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let mut log = String::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap().ends_with(logging::LOG_FILE) {
+                std::io::Read::read_to_string(&mut entry, &mut log).unwrap();
+            }
+        }
+        assert!(log.contains("node is unreachable"));
+        assert!(log.contains("ip-1:22013"));
+        assert!(!log.contains("10.0.0.7"));
+        fs::remove_dir_all(&output.dir).unwrap();
+    }
+}
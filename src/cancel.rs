@@ -0,0 +1,160 @@
+use std::cell::Cell;
+use std::fs;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use tracing::{error, warn};
+
+// Sleeps are cut into steps, so a cancelled run doesn't wait out a long backoff.
+const SLEEP_STEP: Duration = Duration::from_millis(200);
+const PARTIAL_SUFFIX: &str = ".partial";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static RUN_DEADLINE: OnceLock<Instant> = OnceLock::new();
+
+thread_local! {
+    static JOB_DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Why the work stops early.
+#[derive(Clone, Copy, Debug, PartialEq, thiserror::Error)]
+pub enum Cancelled {
+    /// SIGINT or SIGTERM.
+    #[error("interrupted")]
+    Interrupted,
+    /// The job or the whole run is past its deadline.
+    #[error("timed out")]
+    TimedOut,
+}
+
+/// Output cut short by cancellation, kept under `path` with the `.partial` suffix.
+#[derive(Debug, thiserror::Error)]
+#[error("{reason}, incomplete output kept as {path}")]
+pub struct PartialOutput {
+    pub path: String,
+    pub reason: Cancelled,
+}
+
+/// The first SIGINT or SIGTERM cancels the run, the second one exits right away.
+pub fn install() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            error!("interrupted twice, exiting");
+            std::process::exit(crate::summary::EXIT_INTERRUPTED);
+        }
+        warn!("interrupted, finishing running jobs, press Ctrl-C again to exit now");
+    })?;
+    Ok(())
+}
+
+/// No job starts after `timeout` from now and the running ones stop. 0 for no limit.
+pub fn set_run_timeout(timeout: Duration) {
+    if !timeout.is_zero() {
+        let _ = RUN_DEADLINE.set(Instant::now() + timeout);
+    }
+}
+
+/// Work on the current thread stops after `timeout` from now until the guard is
+/// dropped. 0 for no limit.
+pub fn job_timeout(timeout: Duration) -> JobDeadline {
+    let deadline = (!timeout.is_zero()).then(|| Instant::now() + timeout);
+    JobDeadline { previous: JOB_DEADLINE.replace(deadline) }
+}
+
+pub struct JobDeadline {
+    previous: Option<Instant>,
+}
+
+impl Drop for JobDeadline {
+    fn drop(&mut self) {
+        JOB_DEADLINE.set(self.previous);
+    }
+}
+
+/// Checked between reads, so a read blocks for `ssh.read_timeout` at most past the deadline.
+pub fn cancelled() -> Option<Cancelled> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Some(Cancelled::Interrupted);
+    }
+    let now = Instant::now();
+    let past = |deadline: Option<Instant>| deadline.is_some_and(|x| now >= x);
+    if past(RUN_DEADLINE.get().copied()) || past(JOB_DEADLINE.get()) {
+        return Some(Cancelled::TimedOut);
+    }
+    None
+}
+
+pub fn is_cancelled() -> bool {
+    cancelled().is_some()
+}
+
+/// Set by a signal only, timeouts don't stop the upload.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn check() -> Result<()> {
+    match cancelled() {
+        Some(reason) => Err(reason.into()),
+        None => Ok(()),
+    }
+}
+
+/// Returns early when the run is cancelled.
+pub fn sleep(duration: Duration) {
+    let started = Instant::now();
+    while !is_cancelled() {
+        let left = duration.saturating_sub(started.elapsed());
+        if left.is_zero() {
+            break;
+        }
+        thread::sleep(left.min(SLEEP_STEP));
+    }
+}
+
+/// Renames the incomplete output to `<path>.partial`. Returns the error for the collector.
+pub fn mark_partial(path: &str) -> anyhow::Error {
+    let reason = cancelled().unwrap_or(Cancelled::Interrupted);
+    let partial_path = format!("{}{}", path.trim_end_matches('/'), PARTIAL_SUFFIX);
+    match fs::rename(path.trim_end_matches('/'), &partial_path) {
+        Ok(_) => PartialOutput { path: partial_path, reason }.into(),
+        Err(_) => reason.into(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mark_partial() {
+        let dir = std::env::temp_dir().join("harvester_test_cancel");
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("kubelet.log").to_string_lossy().to_string();
+        fs::write(&file, "line 1\nli").unwrap();
+
+        let err = mark_partial(&file);
+        let partial = err.downcast_ref::<PartialOutput>().unwrap();
+        assert_eq!(partial.path, format!("{}.partial", file));
+        assert_eq!(fs::read_to_string(&partial.path).unwrap(), "line 1\nli");
+        assert!(mark_partial(&file).downcast_ref::<Cancelled>().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_job_timeout() {
+        assert_eq!(cancelled(), None);
+        {
+            let _deadline = job_timeout(Duration::from_millis(1));
+            thread::sleep(Duration::from_millis(5));
+            assert_eq!(cancelled(), Some(Cancelled::TimedOut));
+            assert!(check().unwrap_err().to_string().contains("timed out"));
+        }
+        assert_eq!(cancelled(), None);
+        let _deadline = job_timeout(Duration::ZERO);
+        assert_eq!(cancelled(), None);
+    }
+}
@@ -0,0 +1,176 @@
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+use anyhow::{Context, Result, bail};
+
+use crate::summary;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// Not run because a check it depends on failed.
+    Skip,
+}
+
+/// Outcome of one pre-flight check of the `check` subcommand.
+#[derive(Clone, Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl CheckResult {
+    /// Passes with the detail of `result` or fails with its error chain.
+    pub fn from_result(name: &str, result: Result<String>) -> Self {
+        let (status, detail) = match result {
+            Ok(detail) => (CheckStatus::Pass, detail),
+            Err(err) => (CheckStatus::Fail, format!("{:#}", err)),
+        };
+        CheckResult { name: name.to_string(), status, detail }
+    }
+
+    pub fn skip(name: &str, reason: &str) -> Self {
+        CheckResult { name: name.to_string(), status: CheckStatus::Skip, detail: reason.to_string() }
+    }
+}
+
+/// `EXIT_OK` when nothing failed, skipped checks don't count.
+pub fn exit_code(checks: &[CheckResult]) -> i32 {
+    match checks.iter().any(|x| x.status == CheckStatus::Fail) {
+        true => summary::EXIT_FAILED,
+        false => summary::EXIT_OK,
+    }
+}
+
+/// One line per check in the order they ran, then the totals.
+pub fn table(checks: &[CheckResult]) -> String {
+    let mut rows = vec![];
+    for check in checks {
+        rows.push([
+            format!("{:?}", check.status).to_lowercase(),
+            check.name.clone(),
+            check.detail.replace('\n', " "),
+        ]);
+    }
+    let mut result = summary::render_table(["STATUS", "CHECK", "DETAIL"], &rows);
+    let count = |status| checks.iter().filter(|x| x.status == status).count();
+    result.push_str(&format!(
+        "\nchecks: {}, passed: {}, failed: {}, skipped: {}\n",
+        checks.len(),
+        count(CheckStatus::Pass),
+        count(CheckStatus::Fail),
+        count(CheckStatus::Skip),
+    ));
+    result
+}
+
+/// Retention from the output of Loki's `/config` endpoint, `None` when logs are kept forever.
+pub fn loki_retention(config: &str) -> Result<Option<Duration>> {
+    let config: serde_yaml::Value = serde_yaml::from_str(config).context("failed to parse Loki config")?;
+    let enabled = |section: &str, key: &str| config[section][key].as_bool().unwrap_or(false);
+    let period = |section: &str| config[section]["retention_period"].as_str().map(parse_duration).transpose();
+
+    let retention = if enabled("compactor", "retention_enabled") {
+        period("limits_config")?
+    } else if enabled("table_manager", "retention_deletes_enabled") {
+        period("table_manager")?
+    } else {
+        None
+    };
+    Ok(retention.filter(|x| !x.is_zero()))
+}
+
+/// Parses durations in the Prometheus format Loki uses, e.g. `744h` or `1d12h`.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        bail!("empty duration");
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let number: u64 = rest[..digits].parse().with_context(|| format!("invalid duration {:?}", value))?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let secs = match &rest[..unit] {
+            "ms" => 0,
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            "w" => 7 * 86400,
+            "y" => 365 * 86400,
+            _ => bail!("invalid duration {:?}", value),
+        };
+        total += match secs {
+            0 => Duration::from_millis(number),
+            _ => Duration::from_secs(number * secs),
+        };
+        rest = &rest[unit..];
+    }
+    Ok(total)
+}
+
+/// Free bytes on the filesystem of `dir`, or of its closest existing parent.
+pub fn free_space(dir: &str) -> Result<u64> {
+    let mut path = Path::new(dir);
+    while !path.exists() {
+        path = path.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    }
+    let output = process::Command::new("df").arg("-Pk").arg(path).output()?;
+    if !output.status.success() {
+        bail!("df failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    // Filesystem 1024-blocks Used Available Capacity Mounted on
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let available = stdout
+        .lines()
+        .nth(1)
+        .and_then(|x| x.split_whitespace().nth(3))
+        .context("unexpected df output")?;
+    Ok(available.parse::<u64>()? * 1024)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_utils;
+
+    #[test]
+    fn test_loki_retention() {
+        assert_eq!(parse_duration("744h").unwrap(), Duration::from_secs(744 * 3600));
+        assert_eq!(parse_duration("1d12h30m").unwrap(), Duration::from_secs(86400 + 12 * 3600 + 1800));
+        assert!(parse_duration("12x").is_err());
+
+        let config = "compactor:\n  retention_enabled: true\nlimits_config:\n  retention_period: 2w\n";
+        assert_eq!(loki_retention(config).unwrap(), Some(Duration::from_secs(14 * 86400)));
+        let config = "compactor:\n  retention_enabled: false\nlimits_config:\n  retention_period: 2w\n";
+        assert_eq!(loki_retention(config).unwrap(), None);
+        let config = "table_manager:\n  retention_deletes_enabled: true\n  retention_period: 0s\n";
+        assert_eq!(loki_retention(config).unwrap(), None);
+    }
+
+    #[test]
+    fn test_table() {
+        let checks = vec![
+            CheckResult::from_result("ssh 10.0.0.1:22", Ok("authenticated as ptdeploy".to_string())),
+            CheckResult::from_result("ssh 10.0.0.2:22", Err(anyhow::anyhow!("connection refused"))),
+            CheckResult::skip("logcli", "no reachable node"),
+            CheckResult::from_result("loki credentials", Err(ssh_utils::CommandError {
+                status: 1,
+                stderr: "Error response from server: 401 Unauthorized".to_string(),
+            }.into())),
+        ];
+        let table = table(&checks);
+
+        assert!(table.contains("fail    ssh 10.0.0.2:22   connection refused\n"));
+        assert!(table.contains("fail    loki credentials  exit status 1: Error response from server: 401 Unauthorized\n"));
+        assert!(table.ends_with("checks: 4, passed: 1, failed: 2, skipped: 1\n"));
+        assert_eq!(exit_code(&checks), summary::EXIT_FAILED);
+        assert_eq!(exit_code(&checks[2..3]), summary::EXIT_OK);
+        assert_eq!(exit_code(&checks[3..]), summary::EXIT_FAILED);
+    }
+}
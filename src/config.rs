@@ -1,15 +1,49 @@
 use chrono::{DateTime, NaiveDateTime, Utc};
-use serde::{de, Deserialize, Deserializer};
-use std::{fs, sync::Arc};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
 use anyhow::{Result, Context};
 use std::ops::Deref;
 
-use crate::constants;
-
-#[derive(Clone, Debug, Deserialize)]
+/// Names may be globs (`ptaf-*-mgr-*`) or regexes (`re:^ptaf-.*$`), resolved against
+/// the label values in Loki at run time.
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Labels {
     pub app: Vec<String>,
     pub unit: Option<Vec<String>>,
+    /// Names or patterns dropped from both lists.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// A service name of `Labels`.
+#[derive(Clone, Debug)]
+pub enum NamePattern {
+    Exact(String),
+    Glob(glob::Pattern),
+    Regex(regex::Regex),
+}
+
+impl NamePattern {
+    pub fn parse(value: &str) -> Result<Self> {
+        if let Some(regex) = value.strip_prefix("re:") {
+            let regex = regex::Regex::new(regex).with_context(|| format!("invalid service regex {:?}", value))?;
+            return Ok(NamePattern::Regex(regex));
+        }
+        if value.contains(['*', '?', '[']) {
+            let glob = glob::Pattern::new(value).with_context(|| format!("invalid service glob {:?}", value))?;
+            return Ok(NamePattern::Glob(glob));
+        }
+        Ok(NamePattern::Exact(value.to_string()))
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Exact(x) => x == name,
+            NamePattern::Glob(x) => x.matches(name),
+            NamePattern::Regex(x) => x.is_match(name),
+        }
+    }
 }
 
 impl Labels {
@@ -42,16 +76,244 @@ pub enum LabelType {
 }
 
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+pub struct NodeArtifacts {
+    /// systemd units dumped with journalctl for the time window.
+    pub journal: Option<Vec<String>>,
+    /// Remote files, shell globs are allowed.
+    pub files: Option<Vec<String>>,
+    /// Directory with core dumps, only dumps from the time window are taken.
+    pub cores: Option<String>,
+    /// Diagnostic commands, stdout of every command is saved as is.
+    pub commands: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum NodeArtifact {
+    Journal(String),
+    Files(String),
+    Cores(String),
+    Command(String),
+}
+
+impl NodeArtifact {
+    /// Human readable source of the artifact, e.g. `journal kubelet.service`.
+    pub fn describe(&self) -> String {
+        match self {
+            NodeArtifact::Journal(unit) => format!("journal {}", unit),
+            NodeArtifact::Files(pattern) => format!("files {}", pattern),
+            NodeArtifact::Cores(dir) => format!("cores {}", dir),
+            NodeArtifact::Command(cmd) => format!("command {}", cmd),
+        }
+    }
+}
+
+impl NodeArtifacts {
+    pub fn get_artifacts(&self) -> Vec<NodeArtifact> {
+        let mut result = vec![];
+        result.extend(self.journal.iter().flatten().cloned().map(NodeArtifact::Journal));
+        result.extend(self.files.iter().flatten().cloned().map(NodeArtifact::Files));
+        result.extend(self.cores.iter().cloned().map(NodeArtifact::Cores));
+        result.extend(self.commands.iter().flatten().cloned().map(NodeArtifact::Command));
+        result
+    }
+}
+
+/// Logs of a service selected in Loki by `{<label>="<name>"}`, and by the namespace
+/// if set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LokiService {
+    pub name: String,
+    pub label: String,
+    pub with_pods: bool,
+    pub namespace: Option<String>,
+}
+
+impl LokiService {
+    pub fn describe(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("loki {}={} namespace={}", self.label, self.name, namespace),
+            None => format!("loki {}={}", self.label, self.name),
+        }
+    }
+
+    /// Start of the log file name, unique across namespaces.
+    pub fn file_prefix(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}_{}", namespace, self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// Services taken from the label values in Loki instead of the label lists.
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct DiscoverConfig {
+    pub enabled: bool,
+    /// Every value of every label is a service.
+    pub labels: Vec<String>,
+    /// Only streams of these namespaces, all when empty.
+    pub namespaces: Vec<String>,
+    /// Names or patterns of services to skip.
+    pub exclude: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+}
+
+impl LogLevel {
+    /// Spellings of the level and the ones above it, as they appear in logs.
+    pub fn names_from(&self) -> Vec<&'static str> {
+        [
+            (LogLevel::Debug, &["debug"][..]),
+            (LogLevel::Info, &["info"]),
+            (LogLevel::Warn, &["warn", "warning"]),
+            (LogLevel::Error, &["error", "err"]),
+            (LogLevel::Fatal, &["fatal", "critical", "panic"]),
+        ]
+            .into_iter()
+            .filter(|(level, _)| level >= self)
+            .flat_map(|(_, names)| names.iter().copied())
+            .collect()
+    }
+}
+
+/// LogQL stages after the stream selector, in the order they are applied.
+#[derive(Clone, Debug, Deserialize, Serialize, Default, PartialEq)]
+#[serde(default)]
+pub struct LogFilter {
+    /// Lines containing every string, `|= "..."`.
+    pub contains: Vec<String>,
+    /// Lines matching every RE2 regex, `|~ "..."`.
+    pub matches: Vec<String>,
+    /// Lines containing none of the strings, `!= "..."`.
+    pub excludes: Vec<String>,
+    /// Lines matching none of the regexes, `!~ "..."`.
+    pub not_matches: Vec<String>,
+    /// Parser stage, e.g. `json` or `logfmt`.
+    pub parser: Option<String>,
+    /// Label filters after the parser, e.g. `level="error"`, passed as is. Require `parser`.
+    pub label_filters: Vec<String>,
+    /// With a parser the `level` label is filtered, otherwise the line is matched for
+    /// the level as a word.
+    pub min_level: Option<LogLevel>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct LogFilters {
+    pub default: LogFilter,
+    /// By service name, replaces the default.
+    pub services: HashMap<String, LogFilter>,
+}
+
+impl LogFilters {
+    pub fn for_service(&self, name: &str) -> &LogFilter {
+        self.services.get(name).unwrap_or(&self.default)
+    }
+
+    /// Label filters need labels extracted by a parser, without one they would be dropped.
+    pub fn validate(&self) -> Result<()> {
+        let filters = std::iter::once(("default", &self.default))
+            .chain(self.services.iter().map(|(name, filter)| (name.as_str(), filter)));
+        for (name, filter) in filters {
+            if filter.parser.is_none() && !filter.label_filters.is_empty() {
+                anyhow::bail!("log_filters.{}: label_filters need a parser", name);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Artifacts {
     pub cores: bool,
     pub backend: bool,
     pub core_labels: Labels,
     pub infra_labels: Labels,
     pub backend_labels: Labels,
+    #[serde(default)]
+    pub node: NodeArtifacts,
+    #[serde(default)]
+    pub log_filters: LogFilters,
+    #[serde(default)]
+    pub discover: DiscoverConfig,
 }
 
 impl Artifacts {
+    /// Services queried in Loki, in config order. Patterns are replaced with the matching
+    /// values of the label from `values`, sorted, and excluded names are dropped.
+    pub fn resolve_loki_services(&self, mut values: impl FnMut(&str) -> Result<Vec<String>>) -> Result<Vec<LokiService>> {
+        let mut live: HashMap<&str, Vec<String>> = HashMap::new();
+        let mut result: Vec<LokiService> = vec![];
+        for label in self.get_labels() {
+            // Core and backend logs are split by pod, infra ones are not.
+            let (labels, with_pods) = match label {
+                LabelType::CoreLabel(l) | LabelType::BackendLabel(l) => (l, true),
+                LabelType::InfraLabel(l) => (l, false),
+            };
+            let exclude = labels.exclude.iter().map(|x| NamePattern::parse(x)).collect::<Result<Vec<_>>>()?;
+            for (label, names) in [("app", &labels.app), ("unit", &labels.unit.clone().unwrap_or_default())] {
+                for name in names {
+                    let matched = match NamePattern::parse(name)? {
+                        NamePattern::Exact(name) => vec![name],
+                        pattern => {
+                            if !live.contains_key(label) {
+                                let mut found = values(label).with_context(|| format!("failed to resolve {}", name))?;
+                                found.sort();
+                                live.insert(label, found);
+                            }
+                            live[label].iter().filter(|x| pattern.matches(x)).cloned().collect()
+                        },
+                    };
+                    for name in matched {
+                        let excluded = exclude.iter().any(|x| x.matches(&name));
+                        let duplicate = result.iter().any(|x| x.label == label && x.name == name);
+                        if !excluded && !duplicate {
+                            result.push(LokiService { name, label: label.to_string(), with_pods, namespace: None });
+                        }
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Every value of the discovered labels from `values`, per namespace when they are
+    /// set. Services of `configured` with the same name keep being split by pod.
+    pub fn discover_loki_services(
+        &self,
+        configured: &[LokiService],
+        mut values: impl FnMut(&str, Option<&str>) -> Result<Vec<String>>,
+    ) -> Result<Vec<LokiService>> {
+        let discover = &self.discover;
+        let exclude = discover.exclude.iter().map(|x| NamePattern::parse(x)).collect::<Result<Vec<_>>>()?;
+        let namespaces = match discover.namespaces.is_empty() {
+            true => vec![None],
+            false => discover.namespaces.iter().map(|x| Some(x.as_str())).collect(),
+        };
+        let mut result = vec![];
+        for namespace in namespaces {
+            for label in &discover.labels {
+                let mut names = values(label, namespace).with_context(|| format!("failed to discover {} values", label))?;
+                names.sort();
+                for name in names.into_iter().filter(|x| !exclude.iter().any(|p| p.matches(x))) {
+                    let with_pods = configured.iter().any(|x| x.label == *label && x.name == name && x.with_pods);
+                    result.push(LokiService { name, label: label.clone(), with_pods, namespace: namespace.map(String::from) });
+                }
+            }
+        }
+        Ok(result)
+    }
+
     pub fn get_labels(&self) -> Vec<LabelType> {
         let mut result = vec![];
         if self.backend {
@@ -78,17 +340,364 @@ impl Artifacts {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Param {
     pub ssh: SshConfig,
     pub loki: LokiConfig,
+    pub nodes: NodesConfig,
+    pub transfer: TransferConfig,
+    pub output: OutputConfig,
+    pub redaction: RedactionConfig,
+    pub upload: UploadConfig,
+    pub scheduler: SchedulerConfig,
+    pub retry: RetryConfig,
+    pub logging: LoggingConfig,
+    pub progress: ProgressConfig,
+    pub budget: BudgetConfig,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetAction {
+    Abort,
+    /// Asks on a terminal, aborts otherwise.
+    #[default]
+    Prompt,
+    Warn,
+}
+
+/// Limits on the Loki volume estimated from the index before collecting.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BudgetConfig {
+    pub enabled: bool,
+    /// 0 for no limit.
+    pub max_bytes_mb: u64,
+    /// 0 for no limit.
+    pub max_lines: u64,
+    pub on_exceed: BudgetAction,
+}
+
+/// A terminal gets a live view, otherwise a progress line is logged every `interval_secs`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProgressConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LoggingConfig {
+    /// `EnvFilter` directives, e.g. `info` or `info,harvester::ssh_utils=debug`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RetryPolicy {
+    pub max_attempts: u8,
+    /// Delay after the first failure, multiplied by `multiplier` after every next one.
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub multiplier: f64,
+    /// Random share taken off the delay, from 0 to 1.
+    pub jitter: f64,
+    /// No new attempt starts after this time since the first one, 0 for no limit.
+    pub max_elapsed_secs: u64,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/// `default` is used by collectors without their own policy.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RetryConfig {
+    pub default: RetryPolicy,
+    pub loki: Option<RetryPolicy>,
+    pub journal: Option<RetryPolicy>,
+    pub files: Option<RetryPolicy>,
+    pub cores: Option<RetryPolicy>,
+    pub commands: Option<RetryPolicy>,
+    /// Every request of an upload, e.g. a part of a multipart one, is retried on its own.
+    pub upload: Option<RetryPolicy>,
+}
+
+impl RetryConfig {
+    pub fn for_loki(&self) -> &RetryPolicy {
+        self.loki.as_ref().unwrap_or(&self.default)
+    }
+
+    pub fn for_upload(&self) -> &RetryPolicy {
+        self.upload.as_ref().unwrap_or(&self.default)
+    }
+
+    pub fn for_artifact(&self, artifact: &NodeArtifact) -> &RetryPolicy {
+        let policy = match artifact {
+            NodeArtifact::Journal(_) => &self.journal,
+            NodeArtifact::Files(_) => &self.files,
+            NodeArtifact::Cores(_) => &self.cores,
+            NodeArtifact::Command(_) => &self.commands,
+        };
+        policy.as_ref().unwrap_or(&self.default)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SchedulerConfig {
+    /// Collection jobs running at once.
+    pub max_jobs: usize,
+    /// Jobs querying Loki at once.
+    pub loki_jobs: usize,
+    /// Jobs running at once on a single node.
+    pub node_jobs: usize,
+    /// A job still running after this time, retries included, is stopped and keeps
+    /// what it got so far. 0 for no limit.
+    pub job_timeout_secs: u64,
+    /// No job starts after this time since the start and the running ones stop,
+    /// then the bundle is packed as usual. 0 for no limit.
+    pub run_timeout_secs: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadTarget {
+    #[default]
+    None,
+    S3,
+    Http,
+    Sftp,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpMethod {
+    #[default]
+    Put,
+    Post,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UploadConfig {
+    pub target: UploadTarget,
+    pub s3: S3Config,
+    pub http: HttpUploadConfig,
+    pub sftp: SftpUploadConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-central-1.amazonaws.com` or `http://localhost:9000` for MinIO.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to the bundle file name to get the object key.
+    pub prefix: Option<String>,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Larger bundles are uploaded in parts of this size, at least 5 MiB.
+    pub part_size_mb: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HttpUploadConfig {
+    /// The bundle file name is appended if the url ends with `/`.
+    pub url: String,
+    pub method: HttpMethod,
+    /// Value of the `Authorization` header, e.g. `Bearer <token>`.
+    pub auth_header: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SftpUploadConfig {
+    pub host: String,
+    pub port: u16,
+    pub login: String,
+    pub password: Option<String>,
+    /// Used when there is no password.
+    pub key_file: Option<String>,
+    pub dir: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RedactionConfig {
+    pub enabled: bool,
+    /// Replace IPv4 addresses with stable tokens, e.g. `ip-1`.
+    pub pseudonymize_ips: bool,
+    /// Replace emails with stable tokens, e.g. `email-1`.
+    pub pseudonymize_emails: bool,
+    /// Applied after the built-in rules.
+    pub rules: Option<Vec<RedactionRule>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RedactionRule {
+    pub name: String,
+    /// Regular expression, replacement may refer to its groups as `${1}`.
+    pub pattern: String,
+    /// `***` if empty.
+    pub replacement: Option<String>,
+    /// Replace every distinct match with a `<name>-<n>` token instead.
+    #[serde(default)]
+    pub pseudonymize: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BundleFormat {
+    #[default]
+    TarGz,
+    TarZst,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OutputConfig {
+    /// Every run gets its own timestamped directory and bundle here.
+    pub dir: String,
+    pub format: BundleFormat,
+    /// age public keys (`age1...`). When set, only the encrypted bundle is kept.
+    pub recipients: Option<Vec<String>>,
+    /// Split the bundle into volumes of this size, 0 keeps it whole.
+    pub volume_size_mb: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TransferConfig {
+    /// Output is compressed on the node before it is transferred.
+    pub compression: Compression,
+    /// Store compressed output as is instead of decompressing it locally.
+    pub keep_compressed: bool,
+    /// Directories in node files are transferred as a single tar stream.
+    pub tar_directories: bool,
+    /// Compare copies of single files with `sha256sum` of the remote file, compressed
+    /// ones after decompression.
+    pub verify_checksum: bool,
+}
+
+/// How remote commands get root privileges.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Privilege {
+    #[default]
+    None,
+    /// `sudo -S`, the password is fed via stdin.
+    Sudo,
+    /// `sudo -n`, relies on NOPASSWD in sudoers.
+    SudoNopasswd,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum NodeAddr {
+    Addr(String),
+    Node {
+        addr: String,
+        privilege: Option<Privilege>,
+        sudo_password: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeSpec {
+    pub host: String,
+    pub port: u16,
+    pub privilege: Privilege,
+    pub sudo_password: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NodesConfig {
+    /// Static list of nodes, each is `<addr>:<port>` or a map with per node settings.
+    pub addrs: Option<Vec<NodeAddr>>,
+    /// Take InternalIP addresses from the Kubernetes API when `addrs` is empty.
+    pub discover: bool,
+    /// SSH port of discovered nodes.
+    pub port: u16,
+    /// Default privilege escalation of nodes.
+    #[serde(default)]
+    pub privilege: Privilege,
+    /// Password for `sudo`, the SSH password is used if empty.
+    pub sudo_password: Option<String>,
+}
+
+impl NodesConfig {
+    /// Nodes come from the Kubernetes API only when none are configured.
+    pub fn discovered(&self) -> Result<bool> {
+        Ok(self.discover && self.static_nodes()?.is_empty())
+    }
+
+    /// Resolves configured entries, `port` is used when an address has none and
+    /// node level settings fall back to the defaults of the section.
+    pub fn static_nodes(&self) -> Result<Vec<NodeSpec>> {
+        let mut result = vec![];
+        for entry in self.addrs.iter().flatten() {
+            let (addr, privilege, sudo_password) = match entry {
+                NodeAddr::Addr(addr) => (addr, None, None),
+                NodeAddr::Node { addr, privilege, sudo_password } => (addr, *privilege, sudo_password.as_ref()),
+            };
+            let (host, port) = match addr.rsplit_once(':') {
+                Some((host, port)) => {
+                    let port = port.parse::<u16>()
+                        .with_context(|| format!("invalid port in node address: {}", addr))?;
+                    (host.to_string(), port)
+                },
+                None => (addr.to_string(), self.port),
+            };
+            result.push(self.node_spec(host, port, privilege, sudo_password));
+        }
+        Ok(result)
+    }
+
+    /// Node with the defaults of the section, used for discovered nodes.
+    pub fn node_spec(&self, host: String, port: u16, privilege: Option<Privilege>, sudo_password: Option<&String>) -> NodeSpec {
+        NodeSpec {
+            host,
+            port,
+            privilege: privilege.unwrap_or(self.privilege),
+            sudo_password: sudo_password.or(self.sudo_password.as_ref()).cloned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SshConfig {
-    pub addr: String,
     pub login: String,
     pub password: Option<String>,
+    /// Seconds, 0 disables the limit.
+    pub connect_timeout: u64,
+    pub handshake_timeout: u64,
+    /// Limit for a single blocking read or write, seconds. 0 disables it.
+    pub read_timeout: u64,
+    /// Seconds between SSH keepalives, 0 disables them.
+    pub keepalive_interval: u32,
+    /// Total attempts of an idempotent operation when the session breaks, outside
+    /// of jobs. Jobs retry broken sessions under their retry policy.
+    pub reconnect_attempts: u8,
+    pub pool: PoolConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PoolConfig {
+    /// Sessions per node.
+    pub max_size: u32,
+    /// Sessions kept open while idle, `max_size` if empty.
+    pub min_idle: Option<u32>,
+    /// Seconds an idle session is kept, 0 keeps it forever.
+    pub idle_timeout: u64,
+    /// Open and close a channel on a session before handing it out. A keepalive isn't
+    /// enough, it is not sent before its interval has passed.
+    pub test_on_check_out: bool,
 }
 
 impl SshConfig {
@@ -97,7 +706,7 @@ impl SshConfig {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
 pub struct LokiConfig {
     pub login: String,
     pub password: String,
@@ -119,7 +728,7 @@ where
         Some(s) => {
             let dt = NaiveDateTime::parse_from_str(&s, "%Y-%m-%d %H:%M")
                 .map_err(|_| de::Error::custom("Invalid date format"))
-                .and_then(|dt| Ok(DateTime::<Utc>::from_utc(dt, Utc)))?;
+                .map(|dt| dt.and_utc())?;
             Ok(Some(dt))
         },
         None => Ok(None)
@@ -152,7 +761,7 @@ impl Deref for SharedConfig {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     pub artifacts: Artifacts,
     pub param: Param,
@@ -161,7 +770,8 @@ pub struct Config {
 impl Config {
     pub fn from_string(config_str: &str) -> Result<Self> {
         // let contents = fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&config_str)?;
+        let config: Config = serde_yaml::from_str(config_str)?;
+        config.artifacts.log_filters.validate()?;
         Ok(config)
     }
 
@@ -193,12 +803,125 @@ impl Config {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::constants;
 
     #[test]
     fn test_config_from_file() {
         let config = Config::from_string(constants::DEFAULT_CONFIG);
         assert!(config.is_ok());
     }
+
+    #[test]
+    fn test_log_filters() {
+        let mut filters: LogFilters = serde_yaml::from_str(r#"
+        default:
+          contains: [error]
+        services:
+          ptaf-core:
+            parser: json
+            label_filters: ['level="error"']
+        "#).unwrap();
+        assert!(filters.validate().is_ok());
+
+        filters.services.get_mut("ptaf-core").unwrap().parser = None;
+        assert_eq!(
+            filters.validate().unwrap_err().to_string(),
+            "log_filters.ptaf-core: label_filters need a parser",
+        );
+    }
+
+    #[test]
+    fn test_resolve_loki_services() {
+        let mut config = Config::from_string(constants::DEFAULT_CONFIG).unwrap();
+        let artifacts = &mut config.artifacts;
+        artifacts.backend = false;
+        artifacts.core_labels = serde_yaml::from_str(r#"
+        app: [ptaf-core, "ptaf-*-mgr-*", "re:^ptaf-(border|core)$"]
+        unit: [kubelet.service]
+        exclude: ["*-rpc"]
+        "#).unwrap();
+        let live = ["ptaf-conf-mgr-rpc", "ptaf-border", "ptaf-conf-mgr-rest", "ptaf-core", "ptaf-task-mgr-scheduler"];
+        let mut lookups = vec![];
+        let services = artifacts.resolve_loki_services(|label| {
+            lookups.push(label.to_string());
+            Ok(live.iter().map(|x| x.to_string()).collect())
+        }).unwrap();
+
+        let names = services.iter().map(|x| x.describe()).collect::<Vec<_>>();
+        assert_eq!(names, vec![
+            "loki app=ptaf-core",
+            "loki app=ptaf-conf-mgr-rest",
+            "loki app=ptaf-task-mgr-scheduler",
+            "loki app=ptaf-border",
+            "loki unit=kubelet.service",
+        ]);
+        assert_eq!(lookups, vec!["app"]);
+        assert!(services.iter().all(|x| x.with_pods));
+
+        let failed = artifacts.resolve_loki_services(|_| anyhow::bail!("loki is down"));
+        assert!(format!("{:#}", failed.unwrap_err()).contains("failed to resolve ptaf-*-mgr-*: loki is down"));
+
+        artifacts.discover = DiscoverConfig {
+            enabled: true,
+            labels: vec!["app".to_string()],
+            namespaces: vec!["ptaf".to_string(), "infra".to_string()],
+            exclude: vec!["re:-rpc$".to_string()],
+        };
+        let discovered = artifacts.discover_loki_services(&services, |label, namespace| {
+            assert_eq!(label, "app");
+            Ok(match namespace {
+                Some("ptaf") => live.iter().map(|x| x.to_string()).collect(),
+                _ => vec!["rabbitmq".to_string()],
+            })
+        }).unwrap();
+        let names = discovered.iter().map(|x| (x.describe(), x.with_pods)).collect::<Vec<_>>();
+        assert_eq!(names, vec![
+            ("loki app=ptaf-border namespace=ptaf".to_string(), true),
+            ("loki app=ptaf-conf-mgr-rest namespace=ptaf".to_string(), true),
+            ("loki app=ptaf-core namespace=ptaf".to_string(), true),
+            ("loki app=ptaf-task-mgr-scheduler namespace=ptaf".to_string(), true),
+            ("loki app=rabbitmq namespace=infra".to_string(), false),
+        ]);
+        assert_eq!(discovered[4].file_prefix(), "infra_rabbitmq");
+    }
+
+    #[test]
+    fn test_static_nodes() {
+        let data = r#"
+        addrs:
+        - 10.0.0.1:2222
+        - addr: m0-98.local
+          privilege: sudo
+          sudo_password: secret
+        discover: false
+        port: 22013
+        privilege: sudo_nopasswd
+        sudo_password:
+        "#;
+        let mut nodes: NodesConfig = serde_yaml::from_str(data).unwrap();
+        nodes.discover = true;
+        assert!(!nodes.discovered().unwrap());
+        assert_eq!(
+            nodes.static_nodes().unwrap(),
+            vec![
+                NodeSpec {
+                    host: "10.0.0.1".to_string(),
+                    port: 2222,
+                    privilege: Privilege::SudoNopasswd,
+                    sudo_password: None,
+                },
+                NodeSpec {
+                    host: "m0-98.local".to_string(),
+                    port: 22013,
+                    privilege: Privilege::Sudo,
+                    sudo_password: Some("secret".to_string()),
+                },
+            ],
+        );
+        nodes.addrs = None;
+        assert!(nodes.discovered().unwrap());
+    }
 }
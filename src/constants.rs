@@ -1 +1,2 @@
 pub const DEFAULT_CONFIG: &str = include_str!("default_config.yaml");
+pub const KUBECONFIG: &str = "/home/pt/.kube/config";
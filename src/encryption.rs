@@ -0,0 +1,77 @@
+use std::fs;
+use std::io::{self, Write};
+use anyhow::{Result, anyhow, Context};
+
+pub const EXTENSION: &str = "age";
+
+pub fn parse_recipients(keys: &[String]) -> Result<Vec<age::x25519::Recipient>> {
+    keys
+        .iter()
+        .map(|key| key.trim().parse().map_err(|err| anyhow!("invalid recipient {}: {}", key, err)))
+        .collect()
+}
+
+/// Wraps the output so everything written to it is encrypted for every recipient.
+pub fn encrypt<W: Write>(recipients: &[age::x25519::Recipient], output: W) -> Result<age::stream::StreamWriter<W>> {
+    let encryptor = age::Encryptor::with_recipients(recipients.iter().map(|x| x as &dyn age::Recipient))?;
+    Ok(encryptor.wrap_output(output)?)
+}
+
+/// Decrypts a bundle with the private keys from an age identity file.
+/// The output defaults to the bundle path without the `.age` extension.
+pub fn decrypt_file(input: &str, identity_file: &str, output: Option<&str>) -> Result<String> {
+    let output = match output {
+        Some(output) => output.to_string(),
+        None => input
+            .strip_suffix(&format!(".{}", EXTENSION))
+            .with_context(|| format!("can't guess output name for {}, use --output", input))?
+            .to_string(),
+    };
+    let identities = age::IdentityFile::from_file(identity_file.to_string())
+        .with_context(|| format!("can't read identity file {}", identity_file))?
+        .into_identities()?;
+    let decryptor = age::Decryptor::new(io::BufReader::new(fs::File::open(input)?))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|x| x.as_ref()))?;
+
+    // Nothing is left behind if the key doesn't match or the bundle is damaged.
+    let tmp_output = format!("{}.part", output);
+    let result = io::copy(&mut reader, &mut fs::File::create(&tmp_output)?);
+    if let Err(err) = result {
+        fs::remove_file(&tmp_output)?;
+        return Err(err).with_context(|| format!("failed to decrypt {}", input));
+    }
+    fs::rename(&tmp_output, &output)?;
+    Ok(output)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let identity = age::x25519::Identity::generate();
+        let recipients = parse_recipients(&[identity.to_public().to_string()]).unwrap();
+        assert!(parse_recipients(&["age1invalid".to_string()]).is_err());
+
+        let dir = std::env::temp_dir().join("harvester_test_encryption");
+        fs::create_dir_all(&dir).unwrap();
+        let identity_file = dir.join("key.txt");
+        fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+        let bundle = dir.join("bundle.tar.gz.age");
+        let mut writer = encrypt(&recipients, fs::File::create(&bundle).unwrap()).unwrap();
+        writer.write_all(b"bundle data").unwrap();
+        writer.finish().unwrap();
+
+        let output = decrypt_file(bundle.to_str().unwrap(), identity_file.to_str().unwrap(), None).unwrap();
+        assert!(output.ends_with("bundle.tar.gz"));
+        let mut decrypted = String::new();
+        fs::File::open(&output).unwrap().read_to_string(&mut decrypted).unwrap();
+        assert_eq!(decrypted, "bundle data");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
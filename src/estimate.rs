@@ -0,0 +1,134 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+use anyhow::{Result, bail};
+use tracing::warn;
+
+use crate::config::{BudgetAction, BudgetConfig};
+use crate::summary;
+
+/// Volume of a Loki service over the time window, from the index.
+#[derive(Clone, Debug)]
+pub struct ServiceEstimate {
+    /// As in the job name, e.g. `loki app=ptaf-core`.
+    pub service: String,
+    pub lines: Option<u64>,
+    pub bytes: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl ServiceEstimate {
+    pub fn from_result(service: String, result: Result<(u64, u64)>) -> Self {
+        match result {
+            Ok((lines, bytes)) => ServiceEstimate { service, lines: Some(lines), bytes: Some(bytes), error: None },
+            Err(err) => ServiceEstimate { service, lines: None, bytes: None, error: Some(format!("{:#}", err)) },
+        }
+    }
+}
+
+/// The estimate is over a limit of `BudgetConfig`.
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("estimated {what} {estimated} exceed the budget of {limit}")]
+pub struct BudgetExceeded {
+    pub what: &'static str,
+    pub estimated: u64,
+    pub limit: u64,
+}
+
+/// One line per service, the largest first, then the totals.
+pub fn table(estimates: &[ServiceEstimate]) -> String {
+    let mut estimates = estimates.to_vec();
+    estimates.sort_by_key(|x| std::cmp::Reverse(x.bytes));
+
+    let mut rows = vec![];
+    for estimate in &estimates {
+        rows.push([
+            estimate.service.clone(),
+            estimate.lines.map_or("-".to_string(), |x| x.to_string()),
+            estimate.bytes.map_or("-".to_string(), |x| x.to_string()),
+            estimate.error.clone().unwrap_or_default().replace('\n', " "),
+        ]);
+    }
+    let mut result = summary::render_table(["SERVICE", "LINES", "BYTES", "ERROR"], &rows);
+    let (lines, bytes) = totals(&estimates);
+    result.push_str(&format!(
+        "\nservices: {}, estimated lines: {}, estimated bytes: {}, without estimate: {}\n",
+        estimates.len(),
+        lines,
+        bytes,
+        estimates.iter().filter(|x| x.error.is_some()).count(),
+    ));
+    result
+}
+
+/// Services without an estimate count as empty.
+pub fn check_budget(estimates: &[ServiceEstimate], budget: &BudgetConfig) -> Result<(), BudgetExceeded> {
+    let (lines, bytes) = totals(estimates);
+    let max_bytes = budget.max_bytes_mb * 1024 * 1024;
+    if max_bytes > 0 && bytes > max_bytes {
+        return Err(BudgetExceeded { what: "bytes", estimated: bytes, limit: max_bytes });
+    }
+    if budget.max_lines > 0 && lines > budget.max_lines {
+        return Err(BudgetExceeded { what: "lines", estimated: lines, limit: budget.max_lines });
+    }
+    Ok(())
+}
+
+/// Whether the run goes on over the budget. `Prompt` asks on a terminal and aborts
+/// otherwise, `assume_yes` answers for the user.
+pub fn confirm(exceeded: BudgetExceeded, action: BudgetAction, assume_yes: bool) -> Result<()> {
+    match action {
+        BudgetAction::Warn => {
+            warn!("{}, collecting anyway", exceeded);
+            Ok(())
+        },
+        BudgetAction::Prompt if assume_yes => {
+            warn!("{}, continuing as asked", exceeded);
+            Ok(())
+        },
+        BudgetAction::Prompt if io::stdin().is_terminal() => {
+            eprint!("{}, continue? [y/N] ", exceeded);
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            io::stdin().lock().read_line(&mut answer)?;
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => Ok(()),
+                _ => bail!("{}, aborted", exceeded),
+            }
+        },
+        BudgetAction::Prompt | BudgetAction::Abort => bail!("{}, aborted", exceeded),
+    }
+}
+
+fn totals(estimates: &[ServiceEstimate]) -> (u64, u64) {
+    (
+        estimates.iter().filter_map(|x| x.lines).sum(),
+        estimates.iter().filter_map(|x| x.bytes).sum(),
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let estimates = vec![
+            ServiceEstimate::from_result("loki app=ptaf-core".to_string(), Ok((1000, 3 * 1024 * 1024))),
+            ServiceEstimate::from_result("loki app=rabbitmq".to_string(), Ok((500, 1024 * 1024))),
+            ServiceEstimate::from_result("loki unit=kubelet.service".to_string(), Err(anyhow::anyhow!("index stats failed"))),
+        ];
+        let table = table(&estimates);
+        assert!(table.starts_with("SERVICE                    LINES  BYTES    ERROR\nloki app=ptaf-core         1000   3145728\n"));
+        assert!(table.ends_with("services: 3, estimated lines: 1500, estimated bytes: 4194304, without estimate: 1\n"));
+
+        let mut budget = BudgetConfig { enabled: true, max_bytes_mb: 0, max_lines: 0, on_exceed: BudgetAction::Abort };
+        assert_eq!(check_budget(&estimates, &budget), Ok(()));
+        budget.max_bytes_mb = 4;
+        budget.max_lines = 1499;
+        assert_eq!(check_budget(&estimates, &budget), Err(BudgetExceeded { what: "lines", estimated: 1500, limit: 1499 }));
+        budget.max_bytes_mb = 3;
+        let exceeded = check_budget(&estimates, &budget).unwrap_err();
+        assert_eq!(exceeded.to_string(), "estimated bytes 4194304 exceed the budget of 3145728");
+        assert!(confirm(exceeded, BudgetAction::Abort, true).is_err());
+    }
+}
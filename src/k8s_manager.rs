@@ -1,10 +1,12 @@
 use anyhow::Result;
 use serde::Deserialize;
-use serde_yaml::Deserializer;
+use serde::de::DeserializeOwned;
 use std::fs;
-use base64::{Engine as _, alphabet, engine::{self, general_purpose}};
+use base64::{Engine as _, engine::general_purpose};
 use curl::easy::Easy;
 
+use crate::retry;
+
 
 #[derive(Debug, Deserialize)]
 struct ClusterData {
@@ -61,6 +63,7 @@ pub struct Pod {
 #[derive(Debug, Deserialize)]
 pub struct Metadata {
     pub name: String,
+    #[serde(default)]
     pub namespace: String,
 }
 
@@ -69,6 +72,38 @@ pub struct PodList {
     pub items: Vec<Pod>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Node {
+    pub status: NodeStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NodeStatus {
+    #[serde(default)]
+    pub addresses: Vec<NodeAddress>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NodeAddress {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub address: String,
+}
+
+impl Node {
+    pub fn internal_ip(&self) -> Option<&str> {
+        self.status.addresses
+            .iter()
+            .find(|x| x.kind == "InternalIP")
+            .map(|x| x.address.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NodeList {
+    pub items: Vec<Node>,
+}
+
 
 impl KubeConfig {
     fn new(path: &str) -> Result<Self> {
@@ -89,12 +124,20 @@ impl K8SManager {
     }
 
     pub fn get_pods(&self) -> Result<PodList> {
+        self.get("/api/v1/pods")
+    }
+
+    pub fn get_nodes(&self) -> Result<NodeList> {
+        self.get("/api/v1/nodes")
+    }
+
+    fn get<T: DeserializeOwned>(&self, api_path: &str) -> Result<T> {
         let ca_cert = general_purpose::STANDARD.decode(&self.kube_config.clusters[0].cluster.ca_cert)?;
         let cert = general_purpose::STANDARD.decode(&self.kube_config.users[0].user.certificate)?;
         let key = general_purpose::STANDARD.decode(&self.kube_config.users[0].user.key)?;
 
         let kube_api = &self.kube_config.clusters[0].cluster.server;
-        let url = format!("{}/api/v1/pods", kube_api);
+        let url = format!("{}{}", kube_api, api_path);
         
         let mut handle = Easy::new();
         handle.url(&url)?;
@@ -110,15 +153,19 @@ impl K8SManager {
         }).unwrap();
         transfer.perform()?;
         drop(transfer);
+        let code = handle.response_code()?;
+        if code >= 300 {
+            return Err(retry::HttpStatusError { code, body: String::from_utf8_lossy(&buf).to_string() }.into());
+        }
 
         let result = std::str::from_utf8(&buf)?;
-        let result: PodList = serde_json::from_str(result)?;
+        let result: T = serde_json::from_str(result)?;
 
         Ok(result)
     }
 }
 
-
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -170,4 +217,25 @@ mod tests {
         let result: Result<PodList, serde_json::Error> = serde_json::from_str(data);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_node_internal_ip() {
+        let data = r#"{
+            "items": [
+                {
+                    "metadata": {
+                        "name": "m0-98"
+                    },
+                    "status": {
+                        "addresses": [
+                            {"type": "Hostname", "address": "m0-98"},
+                            {"type": "InternalIP", "address": "10.0.0.98"}
+                        ]
+                    }
+                }
+            ]
+        }"#;
+        let result: NodeList = serde_json::from_str(data).unwrap();
+        assert_eq!(result.items[0].internal_ip(), Some("10.0.0.98"));
+    }
 }
@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use anyhow::{Result, anyhow};
+use tracing_subscriber::{EnvFilter, Layer, fmt, prelude::*};
+
+use crate::config::{LogFormat, LoggingConfig};
+use crate::progress;
+
+/// Log of the run, packed into the bundle.
+pub const LOG_FILE: &str = "harvester.log";
+// The file is for debugging the harvester itself, so it is more verbose than the console.
+const FILE_FILTER: &str = "warn,harvester=debug";
+
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+/// Held by tests which open or close `FILE`, it is shared by the whole test binary.
+#[cfg(test)]
+pub static TEST_FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Writes to the log file while it is open, events are dropped otherwise.
+pub(crate) struct FileWriter;
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(file) = FILE.lock().unwrap().as_mut() {
+            file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match FILE.lock().unwrap().as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Console goes to stderr at `config.level`, `RUST_LOG` overrides it.
+/// The log file is always JSON.
+pub fn init(config: &LoggingConfig) -> Result<()> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.level)?,
+    };
+    let console = match config.format {
+        LogFormat::Human => fmt::layer()
+            .with_writer(|| progress::StderrWriter)
+            .with_ansi(io::stderr().is_terminal())
+            .with_thread_names(true)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_writer(|| progress::StderrWriter)
+            .with_span_list(true)
+            .boxed(),
+    };
+    let file = fmt::layer()
+        .json()
+        .with_writer(|| FileWriter)
+        .with_span_list(true)
+        .with_filter(EnvFilter::new(FILE_FILTER));
+    tracing_subscriber::registry()
+        .with(console.with_filter(filter))
+        .with(file)
+        .try_init()
+        .map_err(|err| anyhow!("failed to init logging: {}", err))
+}
+
+/// Starts writing the log file, events before it are on the console only.
+pub fn open_file(path: &Path) -> Result<()> {
+    *FILE.lock().unwrap() = Some(File::create(path)?);
+    Ok(())
+}
+
+/// Stops writing the log file, e.g. before it is packed.
+pub fn close_file() {
+    if let Some(mut file) = FILE.lock().unwrap().take() {
+        let _ = file.flush();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_log_file() {
+        let _lock = TEST_FILE_LOCK.lock().unwrap_or_else(|x| x.into_inner());
+        let path = std::env::temp_dir().join("harvester_test_logging.log");
+        FileWriter.write_all(b"dropped\n").unwrap();
+        open_file(&path).unwrap();
+        FileWriter.write_all(b"{\"message\":\"kept\"}\n").unwrap();
+        close_file();
+        FileWriter.write_all(b"dropped\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"message\":\"kept\"}\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+use std::fmt;
+use anyhow::{Result, bail};
+
+/// Operator of a stream selector or label filter matcher.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchOp {
+    Eq,
+    Neq,
+    Re,
+    Nre,
+}
+
+impl fmt::Display for MatchOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MatchOp::Eq => "=",
+            MatchOp::Neq => "!=",
+            MatchOp::Re => "=~",
+            MatchOp::Nre => "!~",
+        })
+    }
+}
+
+/// Comparison of a label filter with a number, duration or byte size, e.g. `status>=500`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Neq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CmpOp::Eq => "==",
+            CmpOp::Neq => "!=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+        })
+    }
+}
+
+/// Line filter operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineOp {
+    Contains,
+    NotContains,
+    Matches,
+    NotMatches,
+}
+
+impl fmt::Display for LineOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LineOp::Contains => "|=",
+            LineOp::NotContains => "!=",
+            LineOp::Matches => "|~",
+            LineOp::NotMatches => "!~",
+        })
+    }
+}
+
+/// `name op "value"`, the value is escaped when rendered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+}
+
+impl Matcher {
+    pub fn new(name: &str, op: MatchOp, value: &str) -> Self {
+        Matcher { name: name.to_string(), op, value: value.to_string() }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Parser {
+    Json,
+    Logfmt,
+    Unpack,
+    Regexp(String),
+    Pattern(String),
+}
+
+impl Parser {
+    /// `json`, `logfmt`, `unpack`, or `regexp`/`pattern` followed by the expression.
+    pub fn parse(value: &str) -> Result<Self> {
+        let (name, expression) = match value.trim().split_once(char::is_whitespace) {
+            Some((name, expression)) => (name, Some(expression.trim().to_string())),
+            None => (value.trim(), None),
+        };
+        Ok(match (name, expression) {
+            ("json", None) => Parser::Json,
+            ("logfmt", None) => Parser::Logfmt,
+            ("unpack", None) => Parser::Unpack,
+            ("regexp", Some(expression)) => Parser::Regexp(expression),
+            ("pattern", Some(expression)) => Parser::Pattern(expression),
+            _ => bail!("unsupported parser {:?}", value),
+        })
+    }
+}
+
+/// A pipeline stage after the stream selector.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stage {
+    Line(LineOp, String),
+    Parser(Parser),
+    Label(Matcher),
+    /// The value is a number, duration or byte size, it is rendered unquoted.
+    Compare(String, CmpOp, String),
+}
+
+impl Stage {
+    /// A label filter as written in LogQL, e.g. `level="error"` or `status>=500`.
+    pub fn parse_label_filter(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let start = value
+            .find(|c: char| "=!<>".contains(c))
+            .ok_or_else(|| anyhow::anyhow!("no operator in label filter {:?}", value))?;
+        let name = value[..start].trim();
+        let rest = &value[start..];
+        // Longer operators first, so `>=` is not taken for `>`.
+        let ops = [
+            ("==", None, Some(CmpOp::Eq)),
+            ("=~", Some(MatchOp::Re), None),
+            ("!=", Some(MatchOp::Neq), Some(CmpOp::Neq)),
+            ("!~", Some(MatchOp::Nre), None),
+            (">=", None, Some(CmpOp::Ge)),
+            ("<=", None, Some(CmpOp::Le)),
+            ("=", Some(MatchOp::Eq), None),
+            (">", None, Some(CmpOp::Gt)),
+            ("<", None, Some(CmpOp::Lt)),
+        ];
+        let (op, match_op, cmp_op) = ops
+            .into_iter()
+            .find(|(op, _, _)| rest.starts_with(op))
+            .ok_or_else(|| anyhow::anyhow!("invalid operator in label filter {:?}", value))?;
+        let operand = rest[op.len()..].trim();
+        let stage = match (unquote(operand), match_op, cmp_op) {
+            (Some(text), Some(op), _) => Stage::Label(Matcher::new(name, op, &text)),
+            (None, _, Some(op)) => Stage::Compare(name.to_string(), op, operand.to_string()),
+            _ => bail!("invalid value in label filter {:?}", value),
+        };
+        stage.validate()?;
+        Ok(stage)
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            Stage::Label(matcher) => validate_label(&matcher.name),
+            Stage::Compare(name, _, value) => {
+                validate_label(name)?;
+                let literal = !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+                if !literal {
+                    bail!("invalid value {:?} of label filter {}", value, name);
+                }
+                Ok(())
+            },
+            Stage::Line(..) | Stage::Parser(_) => Ok(()),
+        }
+    }
+}
+
+/// A log query: the stream selector and its pipeline.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    pub selector: Vec<Matcher>,
+    pub stages: Vec<Stage>,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Query::default()
+    }
+
+    pub fn matcher(mut self, name: &str, op: MatchOp, value: &str) -> Self {
+        self.selector.push(Matcher::new(name, op, value));
+        self
+    }
+
+    pub fn eq(self, name: &str, value: &str) -> Self {
+        self.matcher(name, MatchOp::Eq, value)
+    }
+
+    pub fn stages(mut self, stages: impl IntoIterator<Item = Stage>) -> Self {
+        self.stages.extend(stages);
+        self
+    }
+
+    /// The selector alone, for endpoints that don't take a pipeline, e.g. index stats.
+    pub fn selector_only(&self) -> Query {
+        Query { selector: self.selector.clone(), stages: vec![] }
+    }
+
+    /// LogQL text with every value escaped. Fails on label names LogQL doesn't allow,
+    /// they can't be escaped.
+    pub fn render(&self) -> Result<String> {
+        if self.selector.is_empty() {
+            bail!("a stream selector needs at least one matcher");
+        }
+        for matcher in &self.selector {
+            validate_label(&matcher.name)?;
+        }
+        for stage in &self.stages {
+            stage.validate()?;
+        }
+
+        let matchers = self.selector
+            .iter()
+            .map(|x| format!("{}{}{}", x.name, x.op, quote(&x.value)))
+            .collect::<Vec<_>>();
+        let mut result = format!("{{{}}}", matchers.join(", "));
+        for stage in &self.stages {
+            let stage = match stage {
+                Stage::Line(op, value) => format!("{} {}", op, quote(value)),
+                Stage::Parser(Parser::Json) => "| json".to_string(),
+                Stage::Parser(Parser::Logfmt) => "| logfmt".to_string(),
+                Stage::Parser(Parser::Unpack) => "| unpack".to_string(),
+                Stage::Parser(Parser::Regexp(x)) => format!("| regexp {}", quote(x)),
+                Stage::Parser(Parser::Pattern(x)) => format!("| pattern {}", quote(x)),
+                Stage::Label(x) => format!("| {}{}{}", x.name, x.op, quote(&x.value)),
+                Stage::Compare(name, op, value) => format!("| {}{}{}", name, op, value),
+            };
+            result.push(' ');
+            result.push_str(&stage);
+        }
+        Ok(result)
+    }
+}
+
+/// Double quoted LogQL string, escaped the way Go strings are.
+pub fn quote(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '"' => result.push_str("\\\""),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Label names can't be escaped, so anything LogQL doesn't allow is refused.
+pub fn validate_label(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("invalid label name {:?}", name);
+    }
+    Ok(())
+}
+
+/// Text of a double quoted or backticked LogQL string, `None` for a bare value.
+fn unquote(value: &str) -> Option<String> {
+    if let Some(raw) = value.strip_prefix('`').and_then(|x| x.strip_suffix('`')) {
+        return Some(raw.to_string());
+    }
+    let inner = value.strip_prefix('"')?.strip_suffix('"')?;
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => result.push('\n'),
+                'r' => result.push('\r'),
+                't' => result.push('\t'),
+                c => result.push(c),
+            },
+            c => result.push(c),
+        }
+    }
+    Some(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let query = Query::new()
+            .eq("app", "ptaf-core")
+            .matcher("instance", MatchOp::Re, "ptaf-core-[0-9]+")
+            .matcher("namespace", MatchOp::Neq, "kube-system")
+            .matcher("container", MatchOp::Nre, "istio.*")
+            .stages([
+                Stage::Line(LineOp::Contains, "say \"hi\"\n".to_string()),
+                Stage::Line(LineOp::NotContains, "healthz".to_string()),
+                Stage::Line(LineOp::Matches, r"code=5\d\d".to_string()),
+                Stage::Line(LineOp::NotMatches, "debug".to_string()),
+                Stage::Parser(Parser::Json),
+                Stage::Label(Matcher::new("level", MatchOp::Eq, "error")),
+                Stage::Compare("duration".to_string(), CmpOp::Ge, "1.5s".to_string()),
+            ]);
+        assert_eq!(
+            query.render().unwrap(),
+            r#"{app="ptaf-core", instance=~"ptaf-core-[0-9]+", namespace!="kube-system", container!~"istio.*"} |= "say \"hi\"\n" != "healthz" |~ "code=5\\d\\d" !~ "debug" | json | level="error" | duration>=1.5s"#,
+        );
+        assert_eq!(query.selector_only().render().unwrap(), r#"{app="ptaf-core", instance=~"ptaf-core-[0-9]+", namespace!="kube-system", container!~"istio.*"}"#);
+
+        assert!(Query::new().render().is_err());
+        assert!(Query::new().eq("app\"} or {x", "y").render().is_err());
+        assert!(Query::new().eq("1app", "y").render().is_err());
+        assert!(Query::new().eq("app", "x").stages([Stage::Compare("status".to_string(), CmpOp::Gt, "1 or 1".to_string())]).render().is_err());
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("plain"), r#""plain""#);
+        assert_eq!(quote(r#"a\b"c"#), r#""a\\b\"c""#);
+        assert_eq!(quote("tab\there\r\u{1}"), r#""tab\there\r\u0001""#);
+        assert_eq!(quote("'$(rm -rf /)'"), r#""'$(rm -rf /)'""#);
+        for value in ["", "x", "a\"b", r"\\", "multi\nline"] {
+            assert_eq!(unquote(&quote(value)).as_deref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Stage::parse_label_filter(r#"level="error""#).unwrap(), Stage::Label(Matcher::new("level", MatchOp::Eq, "error")));
+        assert_eq!(Stage::parse_label_filter(r#"level =~ "warn|error""#).unwrap(), Stage::Label(Matcher::new("level", MatchOp::Re, "warn|error")));
+        assert_eq!(Stage::parse_label_filter(r#"msg!="a \"b\"""#).unwrap(), Stage::Label(Matcher::new("msg", MatchOp::Neq, "a \"b\"")));
+        assert_eq!(Stage::parse_label_filter("path!~`/api/.*`").unwrap(), Stage::Label(Matcher::new("path", MatchOp::Nre, "/api/.*")));
+        assert_eq!(Stage::parse_label_filter("status>=500").unwrap(), Stage::Compare("status".to_string(), CmpOp::Ge, "500".to_string()));
+        assert_eq!(Stage::parse_label_filter("size < 20KB").unwrap(), Stage::Compare("size".to_string(), CmpOp::Lt, "20KB".to_string()));
+        assert_eq!(Stage::parse_label_filter("status == 200").unwrap(), Stage::Compare("status".to_string(), CmpOp::Eq, "200".to_string()));
+        assert!(Stage::parse_label_filter("level").is_err());
+        assert!(Stage::parse_label_filter("level=error").is_err());
+        assert!(Stage::parse_label_filter(r#"status>"500""#).is_err());
+        assert!(Stage::parse_label_filter("status>500 or x").is_err());
+
+        assert_eq!(Parser::parse("json").unwrap(), Parser::Json);
+        assert_eq!(Parser::parse("regexp (?P<ip>\\S+) .*").unwrap(), Parser::Regexp("(?P<ip>\\S+) .*".to_string()));
+        assert!(Parser::parse("json | drop").is_err());
+        assert!(Parser::parse("regexp").is_err());
+    }
+}
@@ -1,19 +1,33 @@
-use std::sync::Arc;
-use anyhow::Result;
-use chrono::{DateTime, Utc};
-use std::path::PathBuf;
-use std::fs;
-use std::thread;
-
-
+use std::sync::{Arc, OnceLock};
+use anyhow::{Context, Result};
+use regex::Regex;
+use base64::{Engine as _, engine::general_purpose};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::bundle;
+use crate::check;
+use crate::constants;
 use crate::k8s_manager;
+use crate::logql;
+use crate::plan;
 use crate::config;
 use crate::ptaf_node;
+use crate::redact;
+use crate::ssh_utils;
+use crate::transfer;
 
 const LIMIT: u32 = 2000000000;
+// What Kubernetes appends to the name of a controller: `<hash>-<id>` for deployments,
+// `<id>` for daemonsets and the ordinal for statefulsets. Generated parts have no vowels.
+const POD_SUFFIX_PATTERN: &str = r"^(?:(?:[bcdfghjklmnpqrstvwxz2456789]+-)?[bcdfghjklmnpqrstvwxz2456789]{5}|\d+)$";
 
+static POD_SUFFIX: OnceLock<Regex> = OnceLock::new();
+
+/// logcli command line. Every argument is quoted for the shell on its own, so values
+/// can't break out of it.
 struct LokiQueryBuilder<'a> {
-    query: Vec<String>,
+    args: Vec<String>,
     time_format: String,
     loki_creds: &'a config::LokiConfig,
 }
@@ -22,138 +36,212 @@ impl<'a> LokiQueryBuilder<'a> {
 
     fn new(time_format: &str, loki_creds: &'a config::LokiConfig) -> Self {
         LokiQueryBuilder {
-            query: vec![],
+            args: vec![],
             time_format: time_format.to_string(),
             loki_creds,
         }
     }
-    
-    fn add_query(&mut self, label: &str, val: &str, instance: Option<&str>, instance_val: Option<&str>) -> &mut Self {
-        let mut q = format!("query \'{{{}=\"{}\"}}\'", label, val);
-        if instance.is_some() && instance_val.is_some() {
-            q = format!(
-                "query \'{{{}=\"{}\", {}=\"{}\"}}\'", label, val, instance.unwrap(), instance_val.unwrap(),
-            );
-        }
-        self.query.push(q);
-        self
+
+    fn add_query(&mut self, query: &logql::Query) -> Result<&mut Self> {
+        self.args.push("query".to_string());
+        self.args.push(query.render()?);
+        Ok(self)
     }
 
-    fn add_instance(&mut self, pod_name: &str) -> &mut Self {
-        let q = format!("instance=\"{}\"", pod_name);
-        self.query.push(q);
+    fn add_labels(&mut self, label: &str) -> &mut Self {
+        self.args.push("labels".to_string());
+        self.args.push(label.to_string());
         self
     }
 
     fn add_from(&mut self) -> &mut Self {
-        let q = format!("--from=\'{}\'", self.loki_creds.log_from.unwrap().format(self.time_format.as_str()));
-        self.query.push(q);
+        self.args.push(format!("--from={}", self.loki_creds.log_from.unwrap().format(self.time_format.as_str())));
         self
     }
 
     fn add_to(&mut self) -> &mut Self {
-        let q = format!("--to=\'{}\'", self.loki_creds.log_to.unwrap().format(self.time_format.as_str()));
-        self.query.push(q);
+        self.args.push(format!("--to={}", self.loki_creds.log_to.unwrap().format(self.time_format.as_str())));
         self
     }
 
     fn add_batch(&mut self, number: u16) -> &mut Self {
-        let q = format!("--batch={}", number.to_string());
-        self.query.push(q);
+        self.args.push(format!("--batch={}", number));
         self
     }
 
     fn add_limit(&mut self, number: u32) -> &mut Self {
-        let q = format!("--limit {}", number.to_string());
-        self.query.push(q);
+        self.args.push(format!("--limit={}", number));
         self
     }
 
     fn add_forward(&mut self) -> &mut Self {
-        self.query.push(String::from("--forward"));
+        self.args.push(String::from("--forward"));
         self
     }
 
     fn add_raw(&mut self) -> &mut Self {
-        self.query.push(String::from("-o raw"));
+        self.args.push(String::from("--output=raw"));
         self
     }
 
-    fn script_head(&self) -> String {
-        let cmd = "/opt/logcli";
-        let user = self.loki_creds.login.clone();
-        let passwd = self.loki_creds.password.clone();
-        let loki_addr = self.loki_creds.full_address();
-        let org_id = self.loki_creds.org_id();
-
-        let mut loki_cmd = format!("{} --username=\"{}\" --password=\"{}\"", cmd, user, passwd);
-        loki_cmd = format!("{} --addr=\"{}\" --org-id=\"{}\" -q", loki_cmd, loki_addr, org_id);
-        loki_cmd
+    /// The password is read from stdin into `LOKI_PASSWORD`, see `get_input`, so it
+    /// doesn't show up in the process list of the node.
+    fn get_query(&self) -> String {
+        let head = [
+            "/opt/logcli".to_string(),
+            format!("--username={}", self.loki_creds.login),
+            format!("--addr={}", self.loki_creds.full_address()),
+            format!("--org-id={}", self.loki_creds.org_id()),
+            "--quiet".to_string(),
+        ];
+        let command = head.iter()
+            .chain(&self.args)
+            .map(|x| ssh_utils::shell_quote(x))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!("IFS= read -r LOKI_PASSWORD && export LOKI_PASSWORD && {}", command)
     }
 
-    fn get_query(&mut self) -> String {
-        self.query.insert(0, self.script_head());
-        self.query.join(" ")
+    /// Stdin of the command of `get_query`.
+    fn get_input(&self) -> String {
+        format!("{}\n", self.loki_creds.password)
     }
 
 }
 
+/// One logcli query of a service and the file it goes to.
+struct LokiTask {
+    query: logql::Query,
+    pod: Option<String>,
+    file: String,
+    source: String,
+}
+
+/// Response of the index stats endpoint, the fields not needed are left out.
+#[derive(Debug, Deserialize)]
+pub struct IndexStats {
+    /// Log lines.
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Response of the label values endpoint.
+#[derive(Debug, Deserialize)]
+struct LabelValues {
+    #[serde(default)]
+    data: Vec<String>,
+}
+
 pub struct LokiWorker {
     pub node: Arc<ptaf_node::PTAFNode>,
     pub config: config::SharedConfig,
-    pub k8s_manager: Arc<k8s_manager::K8SManager>
+    /// Set up front when nodes are discovered, otherwise the kubeconfig is read when pods
+    /// are first filtered. Pods gone from the cluster are skipped when it is available.
+    pub k8s_manager: OnceLock<Option<Arc<k8s_manager::K8SManager>>>,
+    pub redactor: Option<Arc<redact::Redactor>>,
 }
 
 impl LokiWorker {
 
     pub fn collect_without_pods(
         &self,
-        svc_name: &str,
-        label_name: &str,
+        service: &config::LokiService,
         path: &str,
-    ) -> Result<()> {
-        let loki_cmd = LokiQueryBuilder::new(
-            "%Y-%m-%dT%H:%M:%SZ",
-            &self.config.param.loki
-        )
-            .add_query(label_name, svc_name, None, None)
-            .add_batch(5000)
-            .add_from()
-            .add_to()
-            .add_forward()
-            .add_limit(LIMIT)
-            .add_raw()
-            .get_query();
-
-        let local_file = format!(
-            "{}-{}__{}.log",
-            svc_name,
-            self.config.param.loki.log_from.unwrap().format("%Y-%m-%d_%H-%M-%S"),
-            self.config.param.loki.log_to.unwrap().format("%Y-%m-%d_%H-%M-%S"),
-        );
-
-        println!("Loki logs for: {}", svc_name);
-        self.collect(loki_cmd.as_str(), local_file.as_str(), path)?;
-        Ok(())
+    ) -> Result<Vec<bundle::Collected>> {
+        info!("collecting logs");
+        let collected = self.collect(&self.service_task(service)?, path)?;
+        Ok(vec![collected])
     }
 
     pub fn collect_with_pods(
         &self,
-        svc_name: &str,
-        label_name: &str,
+        service: &config::LokiService,
         path: &str,
-    ) -> Result<()> {
+    ) -> Result<Vec<bundle::Collected>> {
+        let tasks = self.pod_tasks(service)?;
+        info!(pods = ?tasks.iter().filter_map(|x| x.pod.as_ref()).collect::<Vec<_>>(), "collecting logs of alive pods");
+        let mut result = vec![];
+        for task in tasks {
+            let _span = tracing::info_span!("pod", pod = task.pod.as_deref()).entered();
+            let item = self.collect(&task, path);
+            result.push(bundle::keep_failed(item, task.source)?);
+        }
+        Ok(result)
+    }
+
+    /// Files the service would be collected into, with the volume from the Loki index.
+    pub fn plan(&self, job: &str, service: &config::LokiService) -> Result<Vec<plan::PlannedItem>> {
+        let tasks = match service.with_pods {
+            true => self.pod_tasks(service)?,
+            false => vec![self.service_task(service)?],
+        };
+        let mut result = vec![];
+        for task in tasks {
+            let item = plan::PlannedItem::new(job, task.source.clone(), None, task.file.clone());
+            result.push(match self.stats(&task) {
+                Ok(stats) if !task.query.stages.is_empty() => item
+                    .with_estimate(stats.bytes)
+                    .with_note("estimated before log filters".to_string()),
+                Ok(stats) => item.with_estimate(stats.bytes),
+                Err(err) => item.with_note(format!("no estimate: {:#}", err)),
+            });
+        }
+        Ok(result)
+    }
+
+    /// Lines and bytes of the whole service over the time window from the Loki index.
+    pub fn estimate(&self, service: &config::LokiService) -> Result<IndexStats> {
+        self.stats(&self.service_task(service)?)
+    }
+
+    fn service_task(&self, service: &config::LokiService) -> Result<LokiTask> {
+        Ok(LokiTask {
+            query: self.query(service)?,
+            pod: None,
+            file: self.file_name(&service.file_prefix()),
+            source: service.describe(),
+        })
+    }
+
+    /// Selector of the service with the pipeline of its log filter.
+    fn query(&self, service: &config::LokiService) -> Result<logql::Query> {
+        let filter = self.config.artifacts.log_filters.for_service(&service.name);
+        let stages = stages(filter).with_context(|| format!("invalid log filter of {}", service.name))?;
+        let query = logql::Query::new().eq(&service.label, &service.name);
+        let query = match &service.namespace {
+            Some(namespace) => query.eq("namespace", namespace),
+            None => query,
+        };
+        Ok(query.stages(stages))
+    }
+
+    /// A task per alive pod of the service.
+    fn pod_tasks(&self, service: &config::LokiService) -> Result<Vec<LokiTask>> {
+        let svc_name = &service.name;
         let loki_pods = self.collect_labels("instance")?
             .into_iter()
-            .filter(|pod| pod.starts_with(svc_name))
+            .filter(|pod| is_pod_of(pod, svc_name))
             .collect::<Vec<String>>();
         // TODO добавить tenant в конфиг
         // let labels = &self.config.artifacts.get_svc_names();
 
-        let alive_pods = self.k8s_manager.get_pods()?   
+        let k8s_manager = self.k8s_manager.get_or_init(|| {
+            match k8s_manager::K8SManager::new(constants::KUBECONFIG) {
+                Ok(k8s_manager) => Some(Arc::new(k8s_manager)),
+                Err(err) => {
+                    warn!("kubeconfig is not available, pods gone from the cluster are collected too: {:#}", err);
+                    None
+                },
+            }
+        });
+        let Some(k8s_manager) = k8s_manager else {
+            return self.instance_tasks(service, loki_pods);
+        };
+        let alive_pods = k8s_manager.get_pods()?   
             .items
             .into_iter()
-            .filter(|x| x.metadata.name.starts_with(svc_name))
+            .filter(|x| is_pod_of(&x.metadata.name, svc_name))
+            .filter(|x| service.namespace.as_ref().is_none_or(|ns| *ns == x.metadata.namespace))
             .map(|x| x.metadata.name)
             .collect::<Vec<_>>();
 
@@ -161,104 +249,264 @@ impl LokiWorker {
             .iter()
             .filter(|x| !alive_pods.contains(x))
             .collect::<Vec<_>>();
+        if !dead_pods.is_empty() {
+            debug!(pods = ?dead_pods, "pods gone from the cluster are skipped");
+        }
+        self.instance_tasks(service, alive_pods)
+    }
+
+    fn instance_tasks(&self, service: &config::LokiService, pods: Vec<String>) -> Result<Vec<LokiTask>> {
+        let query = self.query(service)?;
+        Ok(pods
+            .into_iter()
+            .map(|pod| LokiTask {
+                query: query.clone().eq("instance", &pod),
+                file: self.file_name(&pod),
+                source: format!("{} instance={}", service.describe(), pod),
+                pod: Some(pod),
+            })
+            .collect())
+    }
 
-        println!("harvest from alive pods");
-        println!("alive pods: {:?}, label_name: {}, svc_name: {}", alive_pods, label_name, svc_name);
-        for pod in alive_pods {
-            let loki_cmd = LokiQueryBuilder::new(
-                "%Y-%m-%dT%H:%M:%SZ",
-                &self.config.param.loki
-            )
-                .add_query(label_name, svc_name, Some("instance"), Some(&pod))
-                .add_batch(5000)
-                .add_from()
-                .add_to()
-                .add_forward()
-                .add_limit(LIMIT)
-                .add_raw()
-                .get_query();
-
-            let local_file = format!(
-                "{}-{}__{}.log",
-                pod,
-                self.config.param.loki.log_from.unwrap().format("%Y-%m-%d_%H-%M-%S"),
-                self.config.param.loki.log_to.unwrap().format("%Y-%m-%d_%H-%M-%S"),
-            );
-            self.collect(loki_cmd.as_str(), local_file.as_str(), path)?;
+    /// Values of the label in the streams of the time window, only in `namespace` if set.
+    pub fn label_values(&self, label: &str, namespace: Option<&str>) -> Result<Vec<String>> {
+        let loki = &self.config.param.loki;
+        let mut params = vec![
+            ("start", loki.log_from.unwrap().format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+            ("end", loki.log_to.unwrap().format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        ];
+        if let Some(namespace) = namespace {
+            params.push(("query", logql::Query::new().eq("namespace", namespace).render()?));
         }
-        
-        Ok(())
+        logql::validate_label(label)?;
+        let output = self.api_get(&format!("/loki/api/v1/label/{}/values", label), &params)?;
+        let values: LabelValues = serde_json::from_str(&output)?;
+        Ok(values.data)
     }
 
-    fn collect(
-        &self,
-        loki_cmd: &str,
-        file: &str,
-        path: &str,
-    ) -> Result<()> {
-        let dest_file = format!("{}/{}", path, file);
-        let conn = self.node.get_ssh_conn()?;
-        let envs = self.config.get_envs();
-        println!("collect query: {}", loki_cmd);
-        let res = conn.execute(loki_cmd, envs, None)?;
-        fs::write(dest_file, res.join("\n"))?;
-        Ok(())
+    fn file_name(&self, name: &str) -> String {
+        format!(
+            "{}-{}__{}.log",
+            name,
+            self.config.param.loki.log_from.unwrap().format("%Y-%m-%d_%H-%M-%S"),
+            self.config.param.loki.log_to.unwrap().format("%Y-%m-%d_%H-%M-%S"),
+        )
     }
 
+    /// The command of the task and its stdin.
+    fn command(&self, task: &LokiTask) -> Result<(String, String)> {
+        let mut builder = LokiQueryBuilder::new(
+            "%Y-%m-%dT%H:%M:%SZ",
+            &self.config.param.loki
+        );
+        builder
+            .add_query(&task.query)?
+            .add_batch(5000)
+            .add_from()
+            .add_to()
+            .add_forward()
+            .add_limit(LIMIT)
+            .add_raw();
+        Ok((builder.get_query(), builder.get_input()))
+    }
+
+    /// Volume of the task's streams over the time window from the Loki index, nothing is read.
+    fn stats(&self, task: &LokiTask) -> Result<IndexStats> {
+        let loki = &self.config.param.loki;
+        let output = self.api_get("/loki/api/v1/index/stats", &[
+            ("query", task.query.selector_only().render()?),
+            ("start", loki.log_from.unwrap().format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+            ("end", loki.log_to.unwrap().format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        ])?;
+        Ok(serde_json::from_str(&output)?)
+    }
+
+    /// How long Loki keeps logs, `None` when they are kept forever.
+    pub fn retention(&self) -> Result<Option<std::time::Duration>> {
+        check::loki_retention(&self.api_get("/config", &[])?)
+    }
+
+    /// GET from the Loki HTTP API with curl on the node, Loki is not reachable from here.
+    /// Credentials go in a config on stdin, not on the command line.
+    fn api_get(&self, path: &str, params: &[(&str, String)]) -> Result<String> {
+        let loki = &self.config.param.loki;
+        let auth = general_purpose::STANDARD.encode(format!("{}:{}", loki.login, loki.password));
+        let input = format!("header = \"Authorization: Basic {}\"\n", auth);
+        let mut cmd = format!(
+            "curl -K - -sS --fail -G -H {}",
+            ssh_utils::shell_quote(&format!("X-Scope-OrgID: {}", loki.org_id())),
+        );
+        for (name, value) in params {
+            cmd.push_str(&format!(" --data-urlencode {}", ssh_utils::shell_quote(&format!("{}={}", name, value))));
+        }
+        cmd.push_str(&format!(" {}", ssh_utils::shell_quote(&format!("{}{}", loki.full_address(), path))));
+        // `--fail` makes curl exit with 22 on HTTP errors, e.g. 401.
+        self.node.with_ssh_conn(|conn| conn.output_with_input(&cmd, &input))
+    }
+
+    fn collect(&self, task: &LokiTask, path: &str) -> Result<bundle::Collected> {
+        let dest_file = format!("{}/{}", path, task.file);
+        let (loki_cmd, input) = self.command(task)?;
+        debug!(query = %redact::mask_command(&loki_cmd), "running logcli");
+        let transfer = transfer::Transfer::new(&self.config.param.transfer).with_redactor(self.redactor.as_deref());
+        let local_file = self.node.with_ssh_conn(|conn| {
+            transfer.download_with_input(conn, &loki_cmd, &input, &dest_file)
+        });
+        let text = !self.config.param.transfer.keep_compressed;
+        let collected = local_file.map(|path| bundle::Collected { path, source: task.source.clone(), text, error: None });
+        bundle::keep_partial(collected, task.source.clone())
+    }
+
+    /// Values of the label in the streams of the time window.
     pub fn collect_labels(&self, label: &str) -> Result<Vec<String>> {
-        let loki_cmd = format!("{} labels {}", self.script_head(), label);
-        let conn = self.node.get_ssh_conn()?;
-        let envs = self.config.get_envs();
-        conn.execute(loki_cmd.as_str(), envs, None)
+        let mut builder = LokiQueryBuilder::new("%Y-%m-%dT%H:%M:%SZ", &self.config.param.loki);
+        builder.add_labels(label).add_from().add_to();
+        let (loki_cmd, input) = (builder.get_query(), builder.get_input());
+        // logcli prints HTTP errors, e.g. 401, and exits with 1, so the status is checked.
+        let output = self.node.with_ssh_conn(|conn| conn.output_with_input(&loki_cmd, &input))?;
+        Ok(output.lines().filter(|x| !x.is_empty()).map(String::from).collect())
     }
+}
 
-    // TODO удалить
-    fn script_head(&self) -> String {
-        let cmd = "/opt/logcli";
-        let user = self.config.param.loki.login.clone();
-        let passwd = self.config.param.loki.password.clone();
-        let loki_addr = self.config.param.loki.full_address();
-        let org_id = self.config.param.loki.org_id();
+/// Whether the pod belongs to the service itself, not to another one with a longer name,
+/// e.g. `ptaf-task-mgr-scheduler-0` is not a pod of `ptaf-task-mgr`.
+fn is_pod_of(pod: &str, service: &str) -> bool {
+    let regex = POD_SUFFIX.get_or_init(|| Regex::new(POD_SUFFIX_PATTERN).unwrap());
+    pod.strip_prefix(service)
+        .and_then(|x| x.strip_prefix('-'))
+        .is_some_and(|x| regex.is_match(x))
+}
 
-        let mut loki_cmd = format!("{} --username=\"{}\" --password=\"{}\" ", cmd, user, passwd);
-        loki_cmd = format!("{} --addr=\"{}\" --org-id=\"{}\" -q ", loki_cmd, loki_addr, org_id);
-        loki_cmd
+/// Pipeline of the filter: line filters, then the parser and label filters.
+fn stages(filter: &config::LogFilter) -> Result<Vec<logql::Stage>> {
+    use logql::{LineOp, MatchOp, Stage};
+
+    let mut stages = vec![];
+    stages.extend(filter.contains.iter().map(|x| Stage::Line(LineOp::Contains, x.clone())));
+    stages.extend(filter.matches.iter().map(|x| Stage::Line(LineOp::Matches, x.clone())));
+    stages.extend(filter.excludes.iter().map(|x| Stage::Line(LineOp::NotContains, x.clone())));
+    stages.extend(filter.not_matches.iter().map(|x| Stage::Line(LineOp::NotMatches, x.clone())));
+    if let Some(level) = filter.min_level.filter(|_| filter.parser.is_none()) {
+        let pattern = format!("(?i)\\b({})\\b", level.names_from().join("|"));
+        stages.push(Stage::Line(LineOp::Matches, pattern));
+    }
+    if let Some(parser) = &filter.parser {
+        stages.push(Stage::Parser(logql::Parser::parse(parser)?));
+        for label_filter in &filter.label_filters {
+            stages.push(Stage::parse_label_filter(label_filter)?);
+        }
+        if let Some(level) = filter.min_level {
+            let pattern = format!("(?i)({})", level.names_from().join("|"));
+            stages.push(Stage::Label(logql::Matcher::new("level", MatchOp::Re, &pattern)));
+        }
     }
+    Ok(stages)
 }
 
 
+#[cfg(test)]
 mod tests {
-    use chrono::NaiveDateTime;
+    use chrono::{TimeZone, Utc};
 
     use super::*;
 
+    fn loki_config(password: &str) -> config::LokiConfig {
+        config::LokiConfig {
+            login: "admin".to_string(),
+            password: password.to_string(),
+            log_from: Some(Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap()),
+            log_to: Some(Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, 0).unwrap()),
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_query_builder() {
+        let config = loki_config("123");
+        let query = logql::Query::new().eq("app", "ptaf-conf-mgr").eq("instance", "ptaf-conf-mgr-0");
+        let result = LokiQueryBuilder::new("%Y-%m-%dT%H:%M:%SZ", &config)
+            .add_query(&query)
+            .unwrap()
+            .add_batch(5000)
+            .add_from()
+            .add_to()
+            .add_forward()
+            .add_limit(LIMIT)
+            .add_raw()
+            .get_query();
+        assert_eq!(
+            result,
+            "IFS= read -r LOKI_PASSWORD && export LOKI_PASSWORD && '/opt/logcli' '--username=admin' \
+            '--addr=http://loki.ptaf-infra.svc.cluster.local:3100' '--org-id=3jqM2DLOMbbQzdodO3cO' '--quiet' \
+            'query' '{app=\"ptaf-conf-mgr\", instance=\"ptaf-conf-mgr-0\"}' '--batch=5000' \
+            '--from=2023-01-01T00:00:00Z' '--to=2023-01-02T00:00:00Z' '--forward' '--limit=2000000000' '--output=raw'",
+        );
 
+        let labels = LokiQueryBuilder::new("%Y-%m-%dT%H:%M:%SZ", &config).add_labels("instance").add_from().get_query();
+        assert!(labels.ends_with("'--quiet' 'labels' 'instance' '--from=2023-01-01T00:00:00Z'"));
     }
 
-    // #[test]
-    // fn test_collect_without_pods_query() {
-    //     let mut config = config::LokiConfig::default();
-    //     config.login = "admin".to_string();
-    //     config.password = "123".to_string();
-    //     config.log_from = Some(NaiveDateTime::parse_from_str("2023-01-01 00:00:00", "%Y-%m-%d %H:%M:%S")
-    //         .unwrap().and_utc());
-    //     config.log_to = Some(NaiveDateTime::parse_from_str("2023-01-02 00:00:00", "%Y-%m-%d %H:%M:%S")
-    //         .unwrap().and_utc());
-    //     let result = LokiQueryBuilder::new("%Y-%m-%d_%H-%M-%S", &config)
-    //         .add_query("app", "ptaf-conf-mgr")
-    //         .add_batch(5000)
-    //         .add_from()
-    //         .add_to()
-    //         .add_forward()
-    //         .add_limit(LIMIT)
-    //         .add_raw()
-    //         .get_query();
-    //     let should_eq = "/opt/logcli --username=\"admin\" --password=\"123\" \
-    //     --addr=\"http://loki.ptaf-infra.svc.cluster.local:3100\" --org-id=\"3jqM2DLOMbbQzdodO3cO\" -q query \
-    //     \'{app=\"ptaf-conf-mgr\"}\' --batch=5000 --from=\'2023-01-01_00-00-00\' --to=\'2023-01-02_00-00-00\' \
-    //     --forward --limit 2000000000 -o raw".to_string();
-    //     assert_eq!(result, should_eq);
-    // }
+    #[test]
+    fn test_query_builder_escaping() {
+        let config = loki_config("p\"a's$(id)`x`");
+        let query = logql::Query::new().eq("app", "it's \"quoted\" $HOME");
+        let mut builder = LokiQueryBuilder::new("%Y-%m-%dT%H:%M:%SZ", &config);
+        builder.add_query(&query).unwrap();
+        let result = builder.get_query();
+
+        // Single quotes keep everything literal, a single quote is closed, escaped and reopened.
+        assert!(result.ends_with(r#" 'query' '{app="it'\''s \"quoted\" $HOME"}'"#));
+        // The password goes to stdin only.
+        assert!(!result.contains("p\"a'"));
+        assert_eq!(builder.get_input(), "p\"a's$(id)`x`\n");
+
+        let query = logql::Query::new().eq("app\"}", "x");
+        assert!(LokiQueryBuilder::new("%Y-%m-%dT%H:%M:%SZ", &config).add_query(&query).is_err());
+    }
+
+    #[test]
+    fn test_is_pod_of() {
+        assert!(is_pod_of("ptaf-task-mgr-7d9f8b6c5d-x2k4q", "ptaf-task-mgr"));
+        assert!(is_pod_of("ptaf-task-mgr-x2k4q", "ptaf-task-mgr"));
+        assert!(is_pod_of("ptaf-core-0", "ptaf-core"));
+        assert!(!is_pod_of("ptaf-task-mgr-scheduler-7d9f8b6c5d-x2k4q", "ptaf-task-mgr"));
+        assert!(!is_pod_of("ptaf-task-mgr-scheduler-0", "ptaf-task-mgr"));
+        assert!(!is_pod_of("ptaf-task-mgr-sched", "ptaf-task-mgr"));
+        assert!(!is_pod_of("ptaf-task-mgr", "ptaf-task-mgr"));
+    }
+
+    #[test]
+    fn test_stages() {
+        let render = |filter: &config::LogFilter| {
+            logql::Query::new().eq("app", "ptaf-core").stages(stages(filter).unwrap()).render().unwrap()
+        };
+        assert_eq!(render(&config::LogFilter::default()), r#"{app="ptaf-core"}"#);
+
+        let filter = config::LogFilter {
+            contains: vec!["upstream".to_string()],
+            matches: vec!["code=5\\d\\d".to_string()],
+            excludes: vec!["say \"hi\"".to_string()],
+            not_matches: vec!["healthz".to_string()],
+            min_level: Some(config::LogLevel::Error),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&filter),
+            r#"{app="ptaf-core"} |= "upstream" |~ "code=5\\d\\d" != "say \"hi\"" !~ "healthz" |~ "(?i)\\b(error|err|fatal|critical|panic)\\b""#,
+        );
+
+        let filter = config::LogFilter {
+            parser: Some("json".to_string()),
+            label_filters: vec!["status>=500".to_string(), "method=\"POST\"".to_string()],
+            min_level: Some(config::LogLevel::Warn),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&filter),
+            r#"{app="ptaf-core"} | json | status>=500 | method="POST" | level=~"(?i)(warn|warning|error|err|fatal|critical|panic)""#,
+        );
+
+        let filter = config::LogFilter { parser: Some("xml".to_string()), ..Default::default() };
+        assert!(stages(&filter).is_err());
+    }
 }
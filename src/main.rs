@@ -1,84 +1,266 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 
 use chrono::{Utc, Duration};
+use clap::{Args, Parser, Subcommand};
 use loki_worker::LokiWorker;
-use std::thread::{self, JoinHandle};
 use std::time::Instant;
+use tracing::{error, info, warn};
 
+use crate::bundle::Bundle;
 use crate::config::SharedConfig;
+use crate::node_worker::NodeWorker;
+use crate::scheduler::{Job, Scheduler};
+use crate::summary::JobOutcome;
 
+mod cancel;
+mod check;
+mod estimate;
 mod session_manager;
 mod ssh_utils;
 mod ptaf_node;
 mod k8s_manager;
 mod loki_worker;
+mod node_worker;
+mod transfer;
+mod bundle;
+mod redact;
+mod encryption;
+mod upload;
+mod volumes;
+mod scheduler;
+mod summary;
+mod retry;
+mod logging;
+mod logql;
+mod progress;
+mod plan;
 mod config;
 mod constants;
 
 
-fn colllect_with_pods(services: Option<Vec<String>>, lw: Arc<LokiWorker>, label_name: &str, n_tries: u8) -> Vec<JoinHandle<()>> {
-    let mut threads = vec![];
-    if let Some(units) = services {
-        for unit in units {
-            let l = lw.clone();
-            let label = label_name.to_string();
-            let t = thread::spawn(move || {
-                let mut tries_left = n_tries;
-                loop {
-                    if tries_left == 0 {
-                        println!("failed unit: {} label: {}", &unit, &label);
-                        panic!("asd");
-                    }            
-                    if let Err(err) = l.collect_with_pods(&unit, &label, "/home") {
-                        println!(">>>>>>>>>> {:?} unit: {} label: {} <<<<<<<<<<<<<<", err, &unit, &label);
-                        tries_left -= 1;
-                        std::thread::sleep(std::time::Duration::from_secs(1));
-                        continue; // Повторяем попытку
-                    } else {
-                        break; // Успешно выполнено
-                    }
-                }
-            });
-            threads.push(t);
-        }
+const LOKI_TARGET: &str = "loki";
+
+/// Runs the collector under the retry policy. The result and the outcome of the job
+/// go to the bundle, a panic of the collector fails the job.
+fn collect_with_retries(
+    bundle: &Bundle,
+    node: Option<&str>,
+    source: &str,
+    policy: &config::RetryPolicy,
+    collect: impl Fn() -> anyhow::Result<Vec<bundle::Collected>>,
+) {
+    let started = Instant::now();
+    let what = match node {
+        Some(node) => format!("{} node: {}", source, node),
+        None => source.to_string(),
+    };
+    let (result, attempts) = retry::run(policy, &what, || scheduler::catch_panic(&collect));
+    match result {
+        Ok(collected) => {
+            let records = bundle.add_collected(node, &collected);
+            bundle.add_job(JobOutcome::from_records(source, node, attempts, started.elapsed(), &records));
+        },
+        Err(err) => {
+            bundle.add_failed(node, source, &err);
+            bundle.add_job(JobOutcome::failed(source, node, attempts, started.elapsed(), &err));
+        },
     }
-    threads
-}
-
-fn colllect_without_pods(services: Option<Vec<String>>, lw: Arc<LokiWorker>, label_name: &str, n_tries: u8) -> Vec<JoinHandle<()>> {
-    let mut threads = vec![];
-    if let Some(units) = services {
-        for unit in units {
-            let l = lw.clone();
-            let label = label_name.to_string();
-            let t = thread::spawn(move || {
-                let mut tries_left = n_tries;
-                loop {
-                    if tries_left == 0 {
-                        println!("failed unit: {} label: {}", &unit, &label);
-                        panic!("asd");
-                    }            
-                    if let Err(err) = l.collect_without_pods(&unit, &label, "/home") {
-                        println!(">>>>>>>>>> {:?} unit: {} label: {} <<<<<<<<<<<<<<", err, &unit, &label);
-                        tries_left -= 1;
-                        std::thread::sleep(std::time::Duration::from_secs(1));
-                        continue; // Повторяем попытку
-                    } else {
-                        break; // Успешно выполнено
-                    }
-                }
+}
+
+fn loki_jobs(
+    services: &[config::LokiService],
+    lw: &Arc<LokiWorker>,
+    bundle: &Arc<Bundle>,
+    policy: &config::RetryPolicy,
+) -> Vec<Job> {
+    let mut jobs = vec![];
+    for service in services {
+        let l = lw.clone();
+        let b = bundle.clone();
+        let service = service.clone();
+        let source = service.describe();
+        let policy = policy.clone();
+        jobs.push(Job::new(LOKI_TARGET, source.clone(), move || {
+            let _span = tracing::info_span!("job", service = %service.name, label = %service.label, namespace = service.namespace.as_deref()).entered();
+            collect_with_retries(&b, None, &source, &policy, || match service.with_pods {
+                true => l.collect_with_pods(&service, b.run_dir()),
+                false => l.collect_without_pods(&service, b.run_dir()),
             });
-            threads.push(t);
+        }));
+    }
+    jobs
+}
+
+fn node_jobs(workers: &[Arc<NodeWorker>], bundle: &Arc<Bundle>, artifacts: &[config::NodeArtifact], retry: &config::RetryConfig) -> Vec<Job> {
+    let mut jobs = vec![];
+    for worker in workers {
+        for artifact in artifacts {
+            let artifact = artifact.clone();
+            let w = worker.clone();
+            let b = bundle.clone();
+            let source = artifact.describe();
+            let policy = retry.for_artifact(&artifact).clone();
+            let node = w.node_name();
+            let name = format!("{} node: {}", source, node);
+            jobs.push(Job::new(&node_target(&node), name, move || {
+                let _span = tracing::info_span!("job", node = w.node.name(), artifact = %source).entered();
+                collect_with_retries(&b, Some(&node), &source, &policy, || w.collect(&artifact, b.run_dir()));
+            }));
         }
     }
-    threads
+    jobs
+}
+
+fn node_target(node: &str) -> String {
+    format!("node {}", node)
+}
+
+fn target_node(target: &str) -> Option<&str> {
+    target.strip_prefix("node ")
 }
 
 
+/// Workers of a run, set up the same way for collection and the dry run.
+struct Workers {
+    nodes: Vec<Arc<ptaf_node::PTAFNode>>,
+    loki: Arc<LokiWorker>,
+    /// Loki services with the patterns of the config resolved, or discovered.
+    services: Vec<config::LokiService>,
+    /// Services of the config without streams in the time window.
+    without_streams: Vec<config::LokiService>,
+    node_workers: Vec<Arc<NodeWorker>>,
+    redactor: Option<Arc<redact::Redactor>>,
+}
+
+impl Workers {
+    fn new(config: &SharedConfig) -> anyhow::Result<Self> {
+        // Static nodes don't need the Kubernetes API, the Loki worker reads the kubeconfig
+        // when it filters pods.
+        let k8s_manager = match config.param.nodes.discovered()? {
+            true => Some(Arc::new(k8s_manager::K8SManager::new(constants::KUBECONFIG)?)),
+            false => None,
+        };
+        let nodes = ptaf_node::PTAFNode::from_config(config.clone(), k8s_manager.as_deref())?;
+        info!(nodes = ?nodes.iter().map(|x| x.addr()).collect::<Vec<_>>(), "nodes found");
+
+        let redactor = if config.param.redaction.enabled {
+            Some(Arc::new(redact::Redactor::new(&config.param.redaction)?))
+        } else {
+            None
+        };
+
+        // Loki is cluster wide, so logcli is run from the first node only.
+        let loki = Arc::new(loki_worker::LokiWorker{
+            node: nodes[0].clone(),
+            k8s_manager: k8s_manager.map(|x| OnceLock::from(Some(x))).unwrap_or_default(),
+            config: config.clone(),
+            redactor: redactor.clone(),
+        });
+        let node_workers = nodes
+            .iter()
+            .map(|node| Arc::new(NodeWorker{ node: node.clone(), config: config.clone(), redactor: redactor.clone() }))
+            .collect::<Vec<_>>();
+        let (services, without_streams) = resolve_services(config, &loki)?;
+        Ok(Workers { nodes, loki, services, without_streams, node_workers, redactor })
+    }
+}
+
+
+#[derive(Parser)]
+#[command(version, about = "Collects logs and node artifacts into a bundle")]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    collect: CollectArgs,
+}
+
+#[derive(Args)]
+struct CollectArgs {
+    /// Print every job and the files it would write with estimated volume, collect nothing.
+    #[arg(long)]
+    dry_run: bool,
+    /// Collect even if the estimated Loki volume is over the budget.
+    #[arg(short, long)]
+    yes: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Collect logs and artifacts (default).
+    Collect(CollectArgs),
+    /// Check nodes, Kubernetes, Loki and local disk space before a run.
+    Check,
+    /// Decrypt a bundle encrypted for an age public key.
+    Decrypt {
+        bundle: String,
+        /// age identity file with the private key.
+        #[arg(short, long)]
+        identity: String,
+        /// Defaults to the bundle path without `.age`.
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Reassemble a bundle split into volumes.
+    Join {
+        /// Bundle path, any of its volumes or the volume manifest.
+        volume: String,
+        /// Defaults to the bundle path.
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
+
 fn main() {
-    println!("start");
-    println!("{:?}", std::env::current_dir().unwrap());
-    let mut config = config::Config::from_string(constants::DEFAULT_CONFIG).unwrap();
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Collect(cli.collect)) {
+        Command::Collect(args) => {
+            let result = match args.dry_run {
+                true => dry_run(),
+                false => collect(args.yes),
+            };
+            let code = result.unwrap_or_else(|err| {
+                eprintln!("run failed: {:#}", err);
+                summary::EXIT_FAILED
+            });
+            std::process::exit(code);
+        },
+        Command::Check => {
+            let code = check().unwrap_or_else(|err| {
+                eprintln!("check failed: {:#}", err);
+                summary::EXIT_FAILED
+            });
+            std::process::exit(code);
+        },
+        Command::Decrypt { bundle, identity, output } => {
+            match encryption::decrypt_file(&bundle, &identity, output.as_deref()) {
+                Ok(output) => println!("decrypted: {}", output),
+                Err(err) => {
+                    eprintln!("failed to decrypt {}: {:#}", bundle, err);
+                    std::process::exit(1);
+                },
+            }
+        },
+        Command::Join { volume, output } => {
+            match volumes::join(&volume, output.as_deref()) {
+                Ok(output) => println!("joined: {}", output),
+                Err(err) => {
+                    eprintln!("failed to join {}: {:#}", volume, err);
+                    std::process::exit(1);
+                },
+            }
+        },
+    }
+}
+
+/// Resolves the config of the run and starts logging.
+fn load_config() -> anyhow::Result<SharedConfig> {
+    let mut config = config::Config::from_string(constants::DEFAULT_CONFIG)?;
+    logging::init(&config.param.logging)?;
+    info!(version = env!("CARGO_PKG_VERSION"), cwd = ?std::env::current_dir()?, "start");
     config.param.loki.log_from = Some(Utc::now() - Duration::hours(4));
     config.param.loki.log_to = Some(Utc::now());
     config.param.loki.password = "admin".to_string();
@@ -86,54 +268,319 @@ fn main() {
 
     config.param.ssh.login = "ptdeploy".to_string();
 
-    let shared_config = SharedConfig::new(config);
-    // TODO добавить получение имени хоста
-    let host_name = "m0-98.af.rd.ptsecurity.ru".to_string();
-    println!("hostname: {}", host_name);
+    Ok(SharedConfig::new(config))
+}
 
-    let k8s_manager = Arc::new(k8s_manager::K8SManager::new("/home/pt/.kube/config").unwrap());
-    let node = Arc::new(ptaf_node::PTAFNode::new(host_name, "22013".to_string(), shared_config.clone()));
+/// Loki services of the run and the ones of the config without streams in the time window.
+/// Patterns of the config are matched against the label values in Loki, when Loki can't
+/// be asked only the exact names are kept.
+fn resolve_services(config: &SharedConfig, loki: &LokiWorker) -> anyhow::Result<(Vec<config::LokiService>, Vec<config::LokiService>)> {
+    let mut live: HashMap<String, anyhow::Result<Vec<String>>> = HashMap::new();
+    let mut values = |label: &str| {
+        let values = live.entry(label.to_string()).or_insert_with(|| loki.label_values(label, None));
+        values.as_ref().map(|x| x.clone()).map_err(|err| anyhow::anyhow!("{:#}", err))
+    };
+    let configured = match config.artifacts.resolve_loki_services(&mut values) {
+        Ok(services) => services,
+        Err(err) => {
+            warn!("service patterns skipped: {:#}", err);
+            config.artifacts.resolve_loki_services(|_| Ok(vec![]))?
+        },
+    };
+    // A misspelled name silently collects nothing otherwise.
+    let without_streams = configured
+        .iter()
+        .filter(|x| values(&x.label).is_ok_and(|values| !values.contains(&x.name)))
+        .cloned()
+        .collect::<Vec<_>>();
+    for service in &without_streams {
+        warn!(service = %service.describe(), "configured service has no streams in the time window");
+    }
 
-    let lw = Arc::new(loki_worker::LokiWorker{ node, k8s_manager, config: shared_config.clone() });
-    let now = Instant::now();
-    let mut threads = vec![];
-    let n_tries = 3;
-    for label in shared_config.artifacts.get_labels() {
-        match label {
-            config::LabelType::CoreLabel(l) |
-            config::LabelType::BackendLabel(l) => {
-                let app_threads = colllect_with_pods(
-                    Some(l.app), lw.clone(), "app", n_tries
-                );
-                threads.extend(app_threads);
-
-                let unit_threads = colllect_with_pods(
-                    l.unit, lw.clone(), "unit", n_tries
-                );
-                threads.extend(unit_threads);
+    let services = match config.artifacts.discover.enabled {
+        true => config.artifacts.discover_loki_services(&configured, |label, namespace| loki.label_values(label, namespace))?,
+        false => configured,
+    };
+    info!(services = ?services.iter().map(|x| x.describe()).collect::<Vec<_>>(), "loki services resolved");
+    Ok((services, without_streams))
+}
+
+/// Discovers nodes and pods and prints the plan, nothing is collected.
+fn dry_run() -> anyhow::Result<i32> {
+    let shared_config = load_config()?;
+    let workers = Workers::new(&shared_config)?;
+    let items = plan_items(&shared_config, Some((&workers.loki, &workers.services)), &workers.node_workers);
+    let loki = &shared_config.param.loki;
+    let names = |services: &[config::LokiService]| services.iter().map(|x| x.describe()).collect::<Vec<_>>().join(", ");
+    println!("loki services: {}", names(&workers.services));
+    println!("configured without streams: {}\n", names(&workers.without_streams));
+    println!("{}", plan::table(&items, loki.log_from.unwrap(), loki.log_to.unwrap()));
+    Ok(summary::EXIT_OK)
+}
+
+/// Files every job would write. Failed discovery is kept as a note of the job.
+fn plan_items(
+    config: &SharedConfig,
+    loki: Option<(&LokiWorker, &[config::LokiService])>,
+    node_workers: &[Arc<NodeWorker>],
+) -> Vec<plan::PlannedItem> {
+    let mut items = vec![];
+    for (loki, service) in loki.iter().flat_map(|(loki, services)| services.iter().map(move |x| (loki, x))) {
+        let job = service.describe();
+        match loki.plan(&job, service) {
+            Ok(planned) => items.extend(planned),
+            Err(err) => items.push(plan::PlannedItem::new(&job, job.clone(), None, "-".to_string())
+                .with_note(format!("discovery failed: {:#}", err))),
+        }
+    }
+    for worker in node_workers {
+        for artifact in config.artifacts.node.get_artifacts() {
+            let job = format!("{} node: {}", artifact.describe(), worker.node.name());
+            match worker.plan(&job, &artifact) {
+                Ok(planned) => items.extend(planned),
+                Err(err) => items.push(plan::PlannedItem::new(&job, artifact.describe(), Some(worker.node.name()), "-".to_string())
+                    .with_note(format!("listing failed: {:#}", err))),
             }
+        }
+    }
+    items
+}
 
-            config::LabelType::InfraLabel(l) => {
+/// Checks everything a run depends on and prints pass/fail per item, nothing is collected.
+fn check() -> anyhow::Result<i32> {
+    let shared_config = load_config()?;
+    let mut checks = vec![];
 
-                let app_threads = colllect_without_pods(
-                    Some(l.app), lw.clone(), "app", n_tries
-                );
-                threads.extend(app_threads);
+    let k8s_manager = match shared_config.param.nodes.discovered() {
+        Ok(true) => {
+            let k8s_manager = k8s_manager::K8SManager::new(constants::KUBECONFIG).map(Arc::new);
+            let k8s_check = match &k8s_manager {
+                Ok(k8s_manager) => k8s_manager.get_pods().map(|x| format!("{} pods", x.items.len())),
+                Err(err) => Err(anyhow::anyhow!("failed to read kubeconfig: {:#}", err)),
+            };
+            checks.push(check::CheckResult::from_result("kubernetes api", k8s_check));
+            k8s_manager.ok()
+        },
+        Ok(false) => {
+            checks.push(check::CheckResult::skip("kubernetes api", "nodes are configured, not discovered"));
+            None
+        },
+        Err(err) => {
+            checks.push(check::CheckResult::from_result("kubernetes api", Err(err)));
+            None
+        },
+    };
 
-                let unit_threads = colllect_without_pods(
-                    l.unit, lw.clone(), "unit", n_tries
-                );
-                threads.extend(unit_threads);
+    let mut nodes = vec![];
+    match ptaf_node::PTAFNode::specs(&shared_config, k8s_manager.as_deref()) {
+        Ok(specs) => for spec in specs {
+            let name = format!("ssh {}:{}", spec.host, spec.port);
+            let node = ptaf_node::PTAFNode::new(spec, shared_config.clone()).and_then(|node| {
+                node.with_ssh_conn(|conn| conn.execute("true", String::new(), None))?;
+                Ok(node)
+            });
+            let login = &shared_config.param.ssh.login;
+            match node {
+                Ok(node) => {
+                    checks.push(check::CheckResult::from_result(&name, Ok(format!("authenticated as {}", login))));
+                    nodes.push(Arc::new(node));
+                },
+                Err(err) => checks.push(check::CheckResult::from_result(&name, Err(err))),
+            }
+        },
+        Err(err) => checks.push(check::CheckResult::from_result("nodes", Err(err))),
+    }
 
+    // Loki is queried from the first reachable node, the same way a run does.
+    let loki = nodes.first().map(|node| LokiWorker {
+        node: node.clone(),
+        k8s_manager: k8s_manager.clone().map(|x| OnceLock::from(Some(x))).unwrap_or_default(),
+        config: shared_config.clone(),
+        redactor: None,
+    });
+    match &loki {
+        Some(loki) => {
+            // The exit status tells, output over a pty would end with `\r`.
+            let logcli = loki.node.with_ssh_conn(|conn| conn.output("test -x /opt/logcli"));
+            let logcli = match logcli {
+                Ok(_) => Ok(format!("present on {}", loki.node.name())),
+                Err(err) if err.downcast_ref::<ssh_utils::CommandError>().is_some() => {
+                    Err(anyhow::anyhow!("/opt/logcli is missing or not executable on {}", loki.node.name()))
+                },
+                Err(err) => Err(err),
+            };
+            let logcli_ok = logcli.is_ok();
+            checks.push(check::CheckResult::from_result("logcli", logcli));
+            match logcli_ok {
+                true => checks.push(check::CheckResult::from_result(
+                    "loki credentials",
+                    loki.collect_labels("app").map(|x| format!("{} apps", x.len())),
+                )),
+                false => checks.push(check::CheckResult::skip("loki credentials", "logcli is not available")),
+            }
+            let log_from = shared_config.param.loki.log_from.unwrap();
+            let retention = loki.retention().and_then(|retention| match retention {
+                None => Ok("logs are kept forever".to_string()),
+                Some(retention) => {
+                    let oldest = Utc::now() - Duration::from_std(retention)?;
+                    match log_from >= oldest {
+                        true => Ok(format!("{}h, logs are kept since {}", retention.as_secs() / 3600, oldest)),
+                        false => Err(anyhow::anyhow!("time window starts at {}, logs are kept since {}", log_from, oldest)),
+                    }
+                },
+            });
+            checks.push(check::CheckResult::from_result("loki retention", retention));
+        },
+        None => {
+            for name in ["logcli", "loki credentials", "loki retention"] {
+                checks.push(check::CheckResult::skip(name, "no reachable node"));
             }
+        },
+    }
+
+    let node_workers = nodes
+        .iter()
+        .map(|node| Arc::new(NodeWorker{ node: node.clone(), config: shared_config.clone(), redactor: None }))
+        .collect::<Vec<_>>();
+    let services = match loki.as_ref().map(|loki| resolve_services(&shared_config, loki)) {
+        Some(Ok((services, without_streams))) => {
+            let names = without_streams.iter().map(|x| x.describe()).collect::<Vec<_>>();
+            checks.push(check::CheckResult::from_result("loki services", match names.is_empty() {
+                true => Ok(format!("{} services", services.len())),
+                false => Err(anyhow::anyhow!("configured without streams in the time window: {}", names.join(", "))),
+            }));
+            services
+        },
+        Some(Err(err)) => {
+            checks.push(check::CheckResult::from_result("loki services", Err(err)));
+            vec![]
+        },
+        None => {
+            checks.push(check::CheckResult::skip("loki services", "no reachable node"));
+            vec![]
+        },
+    };
+    let expected = plan_items(&shared_config, loki.as_ref().map(|x| (x, &services[..])), &node_workers)
+        .iter()
+        .filter_map(|x| x.estimated_bytes)
+        .sum::<u64>();
+    let output_dir = &shared_config.param.output.dir;
+    let disk = check::free_space(output_dir).and_then(|free| match free >= expected {
+        true => Ok(format!("{} bytes free in {}, {} expected", free, output_dir, expected)),
+        false => Err(anyhow::anyhow!("{} bytes free in {}, {} expected", free, output_dir, expected)),
+    });
+    checks.push(check::CheckResult::from_result("disk space", disk));
+
+    println!("{}", check::table(&checks));
+    Ok(check::exit_code(&checks))
+}
+
+/// Lines and bytes of every Loki service over the time window, queried in parallel.
+fn estimate_volume(config: &SharedConfig, loki: &LokiWorker, services: &[config::LokiService]) -> Vec<estimate::ServiceEstimate> {
+    let limit = config.param.scheduler.loki_jobs.max(1);
+    let mut estimates = vec![];
+    for chunk in services.chunks(limit) {
+        std::thread::scope(|scope| {
+            let handles = chunk
+                .iter()
+                .map(|service| scope.spawn(|| {
+                    let stats = loki.estimate(service).map(|x| (x.entries, x.bytes));
+                    estimate::ServiceEstimate::from_result(service.describe(), stats)
+                }))
+                .collect::<Vec<_>>();
+            estimates.extend(handles.into_iter().map(|x| x.join().unwrap()));
+        });
+    }
+    estimates
+}
+
+/// Returns the exit code, see `summary::EXIT_*`.
+fn collect(assume_yes: bool) -> anyhow::Result<i32> {
+    let shared_config = load_config()?;
+    let recipients = encryption::parse_recipients(shared_config.param.output.recipients.as_deref().unwrap_or_default())?;
+    cancel::install()?;
+    cancel::set_run_timeout(std::time::Duration::from_secs(shared_config.param.scheduler.run_timeout_secs));
+    let Workers { nodes, loki: lw, services, without_streams, node_workers, redactor } = Workers::new(&shared_config)?;
 
+    // Checked before the bundle is created, so an aborted run leaves nothing behind.
+    let budget = &shared_config.param.budget;
+    if budget.enabled {
+        let estimates = estimate_volume(&shared_config, &lw, &services);
+        println!("{}", estimate::table(&estimates));
+        if let Err(exceeded) = estimate::check_budget(&estimates, budget) {
+            estimate::confirm(exceeded, budget.on_exceed, assume_yes)?;
         }
     }
 
-    for t in threads {
-        t.join().unwrap();
+    let bundle = Arc::new(Bundle::create(&shared_config.param.output, recipients, Utc::now())?);
+    logging::open_file(&Path::new(bundle.run_dir()).join(logging::LOG_FILE))?;
+    info!(run_dir = bundle.run_dir(), "bundle created");
+    let now = Instant::now();
+    let mut jobs = vec![];
+    let retry = &shared_config.param.retry;
+    jobs.extend(node_jobs(&node_workers, &bundle, &shared_config.artifacts.node.get_artifacts(), retry));
+    jobs.extend(loki_jobs(&services, &lw, &bundle, retry.for_loki()));
+
+    let scheduler_config = &shared_config.param.scheduler;
+    let loki = &shared_config.param.loki;
+    let progress = Arc::new(progress::Progress::new(loki.log_from.unwrap(), loki.log_to.unwrap()));
+    let mut scheduler = Scheduler::new(scheduler_config.max_jobs)
+        .limit(LOKI_TARGET, scheduler_config.loki_jobs)
+        .job_timeout(std::time::Duration::from_secs(scheduler_config.job_timeout_secs))
+        .progress(progress.clone());
+    for worker in &node_workers {
+        scheduler = scheduler.limit(&node_target(&worker.node_name()), scheduler_config.node_jobs);
+    }
+    info!(jobs = jobs.len(), "running jobs");
+    let display = progress::Display::start(progress, &shared_config.param.progress);
+    let unrun = scheduler.run(jobs);
+    display.stop();
+    let reason = cancel::cancelled().unwrap_or(cancel::Cancelled::Interrupted);
+    for job in unrun {
+        bundle.add_job(JobOutcome::cancelled(&job.name, target_node(&job.target), reason));
+    }
+
+    let node_addrs = nodes
+        .iter()
+        .map(|x| redactor.as_ref().map_or(x.addr(), |redactor| redactor.redact_str(&x.addr())))
+        .collect::<Vec<_>>();
+    // An interrupted run keeps its bundle local, one past its deadline is uploaded as usual.
+    let uploader = upload::Uploader::new(&shared_config.param.upload, shared_config.param.retry.for_upload()).filter(|_| !cancel::is_interrupted());
+    // A split bundle is uploaded as volumes, the volume manifest is what names them all.
+    let uploaded_name = match shared_config.param.output.volume_size_mb {
+        0 => bundle.file_name(),
+        _ => volumes::manifest_path(&bundle.file_name()),
+    };
+    let upload_record = uploader.as_ref().map(|x| x.record(&uploaded_name));
+    let service_names = bundle::ServiceNames {
+        collected: services.iter().map(|x| x.describe()).collect(),
+        without_streams: without_streams.iter().map(|x| x.describe()).collect(),
+    };
+    let bundle_path = bundle.finish(&shared_config, &node_addrs, service_names, redactor.as_deref(), upload_record)?;
+    let jobs = bundle.jobs();
+    println!("{}", summary::table(&jobs));
+    info!(bundle = %bundle_path, "bundle written");
+    let mut exit_code = summary::exit_code(&jobs);
+    let files = match shared_config.param.output.volume_size_mb {
+        0 => vec![bundle_path],
+        size => volumes::split(&bundle_path, size * 1024 * 1024)?,
+    };
+    if let Some(uploader) = uploader {
+        // Volumes and their manifest go next to the bundle location.
+        for file in &files {
+            match uploader.upload(file) {
+                Ok(record) => info!(url = %record.url, "uploaded"),
+                Err(err) => {
+                    error!(file = %file, "upload failed: {:#}", err);
+                    exit_code = summary::EXIT_PARTIAL;
+                },
+            }
+        }
+    }
+    if cancel::is_interrupted() {
+        exit_code = summary::EXIT_INTERRUPTED;
     }
 
-    let elapsed = now.elapsed();
-    println!("Elapsed: {:.2?}", elapsed);
+    info!(elapsed = ?now.elapsed(), exit_code, "done");
+    Ok(exit_code)
 }
@@ -0,0 +1,213 @@
+use std::sync::Arc;
+use std::path::Path;
+use anyhow::Result;
+use tracing::{debug, info};
+
+use crate::bundle;
+use crate::config;
+use crate::plan;
+use crate::ptaf_node;
+use crate::redact;
+use crate::ssh_utils;
+use crate::transfer;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
+
+/// Collects artifacts which live on the node itself rather than in Loki.
+pub struct NodeWorker {
+    pub node: Arc<ptaf_node::PTAFNode>,
+    pub config: config::SharedConfig,
+    pub redactor: Option<Arc<redact::Redactor>>,
+}
+
+impl NodeWorker {
+
+    /// Name of the node in the bundle, its address is pseudonymized as in the artifacts.
+    pub fn node_name(&self) -> String {
+        match &self.redactor {
+            Some(redactor) => redactor.redact_str(self.node.name()),
+            None => self.node.name().to_string(),
+        }
+    }
+
+    /// Output of the node goes to `<path>/<node name>/`.
+    pub fn node_dir(&self, path: &str) -> String {
+        format!("{}/{}", path, self.node_name())
+    }
+
+    pub fn collect(&self, artifact: &config::NodeArtifact, path: &str) -> Result<Vec<bundle::Collected>> {
+        let node_dir = self.node_dir(path);
+        match artifact {
+            config::NodeArtifact::Journal(unit) => self.collect_journal(unit, &node_dir),
+            config::NodeArtifact::Files(pattern) => self.collect_files(pattern, &node_dir),
+            config::NodeArtifact::Cores(dir) => self.collect_cores(dir, &node_dir),
+            config::NodeArtifact::Command(cmd) => self.collect_command(cmd, &node_dir),
+        }
+    }
+
+    /// Files the artifact would be collected into. Remote files are listed with their
+    /// size, nothing is copied.
+    pub fn plan(&self, job: &str, artifact: &config::NodeArtifact) -> Result<Vec<plan::PlannedItem>> {
+        let node = self.node.name();
+        let item = |source: String, target: String| plan::PlannedItem::new(job, source, Some(node), target);
+        match artifact {
+            config::NodeArtifact::Journal(unit) => {
+                Ok(vec![item(format!("journal {}", unit), format!("{}/journal/{}.log", node, unit))])
+            },
+            config::NodeArtifact::Command(cmd) => {
+                Ok(vec![item(format!("command {}", cmd), format!("{}/commands/{}.txt", node, file_name_from(cmd)))])
+            },
+            config::NodeArtifact::Files(pattern) => self.node.with_ssh_conn(|conn| {
+                let mut result = vec![];
+                for source in conn.execute(&list_files(pattern), self.config.get_envs(), None)? {
+                    let source = source.trim_end_matches('\r');
+                    let item = item(format!("file {}", source), format!("{}/files/{}", node, source.trim_start_matches('/')));
+                    if source.ends_with('/') && !self.config.param.transfer.tar_directories {
+                        result.push(item.with_note("directory, skipped".to_string()));
+                        continue;
+                    }
+                    let cmd = format!("du -sb -- {} 2>/dev/null || true", ssh_utils::shell_quote(source));
+                    let size = conn.execute(&cmd, self.config.get_envs(), None)?
+                        .first()
+                        .and_then(|x| x.split_whitespace().next()?.parse().ok());
+                    result.push(match size {
+                        Some(size) => item.with_estimate(size),
+                        None => item.with_note("size unknown".to_string()),
+                    });
+                }
+                Ok(result)
+            }),
+            config::NodeArtifact::Cores(dir) => self.node.with_ssh_conn(|conn| {
+                let mut result = vec![];
+                for line in conn.execute(&self.find_cores(dir, "-printf '%s %p\\n'"), self.config.get_envs(), None)? {
+                    let Some((size, source)) = line.trim_end_matches('\r').split_once(' ') else {
+                        continue;
+                    };
+                    let file_name = Path::new(source).file_name().and_then(|x| x.to_str()).unwrap_or(source);
+                    let item = item(format!("core {}", source), format!("{}/cores/{}", node, file_name));
+                    result.push(match size.parse() {
+                        Ok(size) => item.with_estimate(size),
+                        Err(_) => item.with_note("size unknown".to_string()),
+                    });
+                }
+                Ok(result)
+            }),
+        }
+    }
+
+    fn collect_journal(&self, unit: &str, node_dir: &str) -> Result<Vec<bundle::Collected>> {
+        let loki = &self.config.param.loki;
+        let cmd = format!(
+            "journalctl -u {} --since '{}' --until '{}' --no-pager -o short-iso",
+            ssh_utils::shell_quote(unit),
+            loki.log_from.unwrap().format(TIME_FORMAT),
+            loki.log_to.unwrap().format(TIME_FORMAT),
+        );
+        let dest_file = format!("{}/journal/{}.log", node_dir, unit);
+        info!(unit, "collecting journal");
+        let collected = self.execute_to_file(&cmd, &dest_file, format!("journal {}", unit))?;
+        Ok(vec![collected])
+    }
+
+    fn collect_files(&self, pattern: &str, node_dir: &str) -> Result<Vec<bundle::Collected>> {
+        let cmd = list_files(pattern);
+        let transfer = transfer::Transfer::new(&self.config.param.transfer).with_redactor(self.redactor.as_deref());
+        self.node.with_ssh_conn(|conn| {
+            let mut result = vec![];
+            for source in conn.execute(&cmd, self.config.get_envs(), None)? {
+                let source = source.trim_end_matches('\r');
+                let dest_file = format!("{}/files/{}", node_dir, source.trim_start_matches('/'));
+                let item = if !source.ends_with('/') {
+                    transfer.download_file(conn, source, &dest_file)
+                } else if self.config.param.transfer.tar_directories {
+                    transfer.download_dir(conn, source, &dest_file)
+                } else {
+                    debug!(source, "skipping directory");
+                    continue;
+                };
+                let source = format!("file {}", source);
+                let item = item.map(|path| self.collected(path, source.clone(), true));
+                result.push(bundle::keep_failed(item, source)?);
+            }
+            Ok(result)
+        })
+    }
+
+    fn collect_cores(&self, dir: &str, node_dir: &str) -> Result<Vec<bundle::Collected>> {
+        let cmd = self.find_cores(dir, "-print");
+        let transfer = transfer::Transfer::new(&self.config.param.transfer).with_redactor(self.redactor.as_deref());
+        self.node.with_ssh_conn(|conn| {
+            let mut result = vec![];
+            for source in conn.execute(&cmd, self.config.get_envs(), None)? {
+                let source = source.trim_end_matches('\r');
+                let file_name = Path::new(source)
+                    .file_name()
+                    .and_then(|x| x.to_str())
+                    .unwrap_or(source);
+                let dest_file = format!("{}/cores/{}", node_dir, file_name);
+                let item = transfer.download_file(conn, source, &dest_file);
+                let source = format!("core {}", source);
+                let item = item.map(|path| self.collected(path, source.clone(), false));
+                result.push(bundle::keep_failed(item, source)?);
+            }
+            Ok(result)
+        })
+    }
+
+    fn collect_command(&self, cmd: &str, node_dir: &str) -> Result<Vec<bundle::Collected>> {
+        let dest_file = format!("{}/commands/{}.txt", node_dir, file_name_from(cmd));
+        let collected = self.execute_to_file(cmd, &dest_file, format!("command {}", cmd))?;
+        Ok(vec![collected])
+    }
+
+    fn execute_to_file(&self, cmd: &str, dest_file: &str, source: String) -> Result<bundle::Collected> {
+        let transfer = transfer::Transfer::new(&self.config.param.transfer).with_redactor(self.redactor.as_deref());
+        let local_path = self.node.with_ssh_conn(|conn| {
+            transfer.download(conn, cmd, dest_file)
+        });
+        bundle::keep_partial(local_path.map(|x| self.collected(x, source.clone(), true)), source)
+    }
+
+    /// Core dumps of the time window in `dir`, `action` is what find does with each.
+    /// A missing `dir` lists nothing, a refused `sudo` still fails.
+    fn find_cores(&self, dir: &str, action: &str) -> String {
+        let loki = &self.config.param.loki;
+        format!(
+            "find {} -maxdepth 1 -type f -newermt '{}' ! -newermt '{}' {} 2>/dev/null || true",
+            ssh_utils::shell_quote(dir),
+            loki.log_from.unwrap().format(TIME_FORMAT),
+            loki.log_to.unwrap().format(TIME_FORMAT),
+            action,
+        )
+    }
+
+    /// Compressed output kept as is is never counted in lines.
+    fn collected(&self, path: String, source: String, text: bool) -> bundle::Collected {
+        bundle::Collected { path, source, text: text && !self.config.param.transfer.keep_compressed, error: None }
+    }
+}
+
+/// `-p` marks directories with a trailing slash.
+fn list_files(pattern: &str) -> String {
+    format!("ls -1dp -- {} 2>/dev/null || true", pattern)
+}
+
+/// Turns a shell command into something usable as a file name, e.g. `df -h` -> `df_-h`.
+fn file_name_from(cmd: &str) -> String {
+    cmd
+        .chars()
+        .map(|x| if x.is_ascii_alphanumeric() || x == '-' || x == '.' { x } else { '_' })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_name_from() {
+        assert_eq!(file_name_from("df -h"), "df_-h");
+        assert_eq!(file_name_from("cat /proc/meminfo"), "cat__proc_meminfo");
+    }
+}
@@ -0,0 +1,93 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::summary;
+
+/// A file the run would write, as shown by `--dry-run`.
+#[derive(Clone, Debug, Serialize)]
+pub struct PlannedItem {
+    pub job: String,
+    /// What the item is taken from, e.g. `loki app=ptaf-core instance=ptaf-core-0`.
+    pub source: String,
+    pub node: Option<String>,
+    /// Relative to the run directory.
+    pub target: String,
+    /// Unknown for command output and journals.
+    pub estimated_bytes: Option<u64>,
+    /// Why the item would be skipped or the estimate is missing.
+    pub note: Option<String>,
+}
+
+impl PlannedItem {
+    pub fn new(job: &str, source: String, node: Option<&str>, target: String) -> Self {
+        PlannedItem {
+            job: job.to_string(),
+            source,
+            node: node.map(|x| x.to_string()),
+            target,
+            estimated_bytes: None,
+            note: None,
+        }
+    }
+
+    pub fn with_estimate(mut self, bytes: u64) -> Self {
+        self.estimated_bytes = Some(bytes);
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.note = Some(note);
+        self
+    }
+}
+
+/// One line per item in job order, then the totals.
+pub fn table(items: &[PlannedItem], from: DateTime<Utc>, to: DateTime<Utc>) -> String {
+    let mut rows = vec![];
+    for item in items {
+        rows.push([
+            item.node.clone().unwrap_or("-".to_string()),
+            item.source.clone(),
+            item.target.clone(),
+            item.estimated_bytes.map_or("-".to_string(), |x| x.to_string()),
+            item.note.clone().unwrap_or_default().replace('\n', " "),
+        ]);
+    }
+    let mut result = format!("time window: {} - {}\n\n", from, to);
+    result.push_str(&summary::render_table(["NODE", "SOURCE", "TARGET", "ESTIMATE", "NOTE"], &rows));
+    let mut jobs = items.iter().map(|x| &x.job).collect::<Vec<_>>();
+    jobs.dedup();
+    result.push_str(&format!(
+        "\njobs: {}, files: {}, estimated bytes: {}, without estimate: {}\n",
+        jobs.len(),
+        items.len(),
+        items.iter().filter_map(|x| x.estimated_bytes).sum::<u64>(),
+        items.iter().filter(|x| x.estimated_bytes.is_none()).count(),
+    ));
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let from = Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap();
+        let items = vec![
+            PlannedItem::new("loki app=ptaf-core", "loki app=ptaf-core instance=ptaf-core-0".to_string(), None, "ptaf-core-0.log".to_string())
+                .with_estimate(1024),
+            PlannedItem::new("loki app=ptaf-core", "loki app=ptaf-core instance=ptaf-core-1".to_string(), None, "ptaf-core-1.log".to_string())
+                .with_note("index stats failed".to_string()),
+            PlannedItem::new("command uptime", "command uptime".to_string(), Some("m0"), "m0/commands/uptime.txt".to_string()),
+        ];
+        let table = table(&items, from, from + chrono::Duration::hours(4));
+
+        assert!(table.starts_with("time window: 2024-05-01 10:00:00 UTC - 2024-05-01 14:00:00 UTC\n"));
+        assert!(table.contains("m0    command uptime"));
+        assert!(table.ends_with("jobs: 2, files: 3, estimated bytes: 1024, without estimate: 2\n"));
+    }
+}
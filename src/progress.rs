@@ -0,0 +1,399 @@
+use std::cell::RefCell;
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use regex::Regex;
+use tracing::info;
+
+use crate::config::ProgressConfig;
+
+const TTY_REFRESH: Duration = Duration::from_millis(500);
+// Lines of running jobs shown on a terminal, the rest is summed up in one line.
+const MAX_JOB_LINES: usize = 20;
+// A timestamp is looked for only at the start of a line, e.g. after `{"ts":"`.
+const TIMESTAMP_PREFIX: usize = 64;
+const NO_TIMESTAMP: i64 = i64::MIN;
+
+/// Lines drawn on the terminal, erased before anything else is written to stderr.
+static DRAWN: Mutex<usize> = Mutex::new(0);
+static TIMESTAMP: OnceLock<Regex> = OnceLock::new();
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Counters>>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum JobState {
+    Queued,
+    Running,
+    Done,
+}
+
+/// Updated by the thread running the job, read by the display.
+#[derive(Default)]
+struct Counters {
+    bytes: AtomicU64,
+    lines: AtomicU64,
+    /// Millis of the last timestamp seen in the output.
+    last_timestamp: AtomicI64,
+}
+
+struct JobProgress {
+    name: String,
+    state: JobState,
+    started: Option<Instant>,
+    counters: Arc<Counters>,
+}
+
+/// State of the jobs of a run. Output of a running job is counted through
+/// `CountingReader` and `add_bytes`, which find the job of the current thread.
+pub struct Progress {
+    jobs: Mutex<Vec<JobProgress>>,
+    started: Instant,
+    /// Time window of the run in millis, the share covered by a job gives its ETA.
+    window: (i64, i64),
+}
+
+impl Progress {
+
+    pub fn new(from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
+        Progress {
+            jobs: Mutex::new(vec![]),
+            started: Instant::now(),
+            window: (from.timestamp_millis(), to.timestamp_millis()),
+        }
+    }
+
+    /// Registers a queued job. Returns its id.
+    pub fn add(&self, name: &str) -> usize {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.push(JobProgress {
+            name: name.to_string(),
+            state: JobState::Queued,
+            started: None,
+            counters: Arc::new(Counters { last_timestamp: AtomicI64::new(NO_TIMESTAMP), ..Default::default() }),
+        });
+        jobs.len() - 1
+    }
+
+    /// Output read on this thread is counted for the job until the guard is dropped.
+    pub fn start(self: &Arc<Self>, id: usize) -> JobGuard {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = &mut jobs[id];
+        job.state = JobState::Running;
+        job.started = Some(Instant::now());
+        let counters = job.counters.clone();
+        CURRENT.with(|x| *x.borrow_mut() = Some(counters));
+        JobGuard { progress: self.clone(), id }
+    }
+
+    fn finish(&self, id: usize) {
+        self.jobs.lock().unwrap()[id].state = JobState::Done;
+        CURRENT.with(|x| *x.borrow_mut() = None);
+    }
+
+    /// Share of the time window covered by the output, if it has timestamps.
+    fn covered(&self, counters: &Counters) -> Option<f64> {
+        let last = counters.last_timestamp.load(Ordering::Relaxed);
+        let (from, to) = self.window;
+        if last == NO_TIMESTAMP || to <= from {
+            return None;
+        }
+        Some(((last - from) as f64 / (to - from) as f64).clamp(0.0, 1.0))
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let jobs = self.jobs.lock().unwrap();
+        let count = |state| jobs.iter().filter(|x| x.state == state).count();
+        let mut snapshot = Snapshot {
+            queued: count(JobState::Queued),
+            running: vec![],
+            done: count(JobState::Done),
+            bytes: jobs.iter().map(|x| x.counters.bytes.load(Ordering::Relaxed)).sum(),
+            lines: jobs.iter().map(|x| x.counters.lines.load(Ordering::Relaxed)).sum(),
+            elapsed: self.started.elapsed(),
+            eta: None,
+        };
+        // Done jobs count as covered, queued ones as not started. Running ones without
+        // timestamps are left out of the estimate.
+        let mut covered = snapshot.done as f64;
+        let mut known = snapshot.done + snapshot.queued;
+        for job in jobs.iter().filter(|x| x.state == JobState::Running) {
+            let share = self.covered(&job.counters);
+            let elapsed = job.started.map(|x| x.elapsed()).unwrap_or_default();
+            if let Some(share) = share {
+                covered += share;
+                known += 1;
+            }
+            snapshot.running.push(RunningJob {
+                name: job.name.clone(),
+                bytes: job.counters.bytes.load(Ordering::Relaxed),
+                lines: job.counters.lines.load(Ordering::Relaxed),
+                covered: share,
+                eta: share.and_then(|x| eta(elapsed, x)),
+            });
+        }
+        if known > 0 {
+            snapshot.eta = eta(snapshot.elapsed, covered / known as f64);
+        }
+        snapshot
+    }
+}
+
+/// Marks the job done when dropped.
+pub struct JobGuard {
+    progress: Arc<Progress>,
+    id: usize,
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.progress.finish(self.id);
+    }
+}
+
+/// Counts bytes and lines read for the job of the current thread.
+pub struct CountingReader<R> {
+    inner: R,
+    counters: Option<Arc<Counters>>,
+    /// Lines and timestamps are looked for in text only, not in compressed data.
+    text: bool,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R, text: bool) -> Self {
+        CountingReader { inner, counters: CURRENT.with(|x| x.borrow().clone()), text }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        let Some(counters) = &self.counters else {
+            return Ok(bytes_read);
+        };
+        let data = &buf[..bytes_read];
+        counters.bytes.fetch_add(bytes_read as u64, Ordering::Relaxed);
+        if !self.text {
+            return Ok(bytes_read);
+        }
+        counters.lines.fetch_add(data.iter().filter(|x| **x == b'\n').count() as u64, Ordering::Relaxed);
+        // Only the last complete line of a chunk is looked at, it is the latest one.
+        if let Some(end) = data.iter().rposition(|x| *x == b'\n') {
+            let start = data[..end].iter().rposition(|x| *x == b'\n').map_or(0, |x| x + 1);
+            if let Some(millis) = line_timestamp(&data[start..end]) {
+                counters.last_timestamp.store(millis, Ordering::Relaxed);
+            }
+        }
+        Ok(bytes_read)
+    }
+}
+
+/// Counts bytes copied outside of a reader, e.g. over SFTP.
+pub fn add_bytes(bytes: u64) {
+    CURRENT.with(|x| {
+        if let Some(counters) = x.borrow().as_ref() {
+            counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Timestamps without an offset are taken as UTC, like the time window.
+fn line_timestamp(line: &[u8]) -> Option<i64> {
+    let regex = TIMESTAMP.get_or_init(|| Regex::new(r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}").unwrap());
+    let prefix = String::from_utf8_lossy(&line[..line.len().min(TIMESTAMP_PREFIX)]);
+    let found = regex.find(&prefix)?.as_str().replace(' ', "T");
+    let time = NaiveDateTime::parse_from_str(&found, "%Y-%m-%dT%H:%M:%S").ok()?;
+    Some(time.and_utc().timestamp_millis())
+}
+
+fn eta(elapsed: Duration, covered: f64) -> Option<Duration> {
+    if covered <= 0.0 {
+        return None;
+    }
+    Some(elapsed.mul_f64((1.0 - covered) / covered))
+}
+
+struct RunningJob {
+    name: String,
+    bytes: u64,
+    lines: u64,
+    covered: Option<f64>,
+    eta: Option<Duration>,
+}
+
+struct Snapshot {
+    queued: usize,
+    running: Vec<RunningJob>,
+    done: usize,
+    bytes: u64,
+    lines: u64,
+    elapsed: Duration,
+    eta: Option<Duration>,
+}
+
+impl Snapshot {
+    fn header(&self) -> String {
+        let throughput = self.bytes as f64 / self.elapsed.as_secs_f64().max(1.0);
+        format!(
+            "jobs: {} queued, {} running, {} done | {} in {} lines, {}/s | eta {}",
+            self.queued,
+            self.running.len(),
+            self.done,
+            human_bytes(self.bytes as f64),
+            self.lines,
+            human_bytes(throughput),
+            human_duration(self.eta),
+        )
+    }
+
+    fn job_lines(&self) -> Vec<String> {
+        let mut lines = self.running
+            .iter()
+            .take(MAX_JOB_LINES)
+            .map(|x| format!(
+                "  {}: {}, {} lines, {}, eta {}",
+                x.name,
+                human_bytes(x.bytes as f64),
+                x.lines,
+                x.covered.map_or("-".to_string(), |x| format!("{:.0}%", x * 100.0)),
+                human_duration(x.eta),
+            ))
+            .collect::<Vec<_>>();
+        if self.running.len() > MAX_JOB_LINES {
+            lines.push(format!("  ... {} more", self.running.len() - MAX_JOB_LINES));
+        }
+        lines
+    }
+}
+
+fn human_bytes(bytes: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+fn human_duration(duration: Option<Duration>) -> String {
+    match duration {
+        Some(x) => format!("{}m{:02}s", x.as_secs() / 60, x.as_secs() % 60),
+        None => "-".to_string(),
+    }
+}
+
+/// Redraws the running jobs on a terminal, logs a progress line every
+/// `interval_secs` otherwise.
+pub struct Display {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Display {
+
+    pub fn start(progress: Arc<Progress>, config: &ProgressConfig) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        if !config.enabled {
+            return Display { stop, handle: None };
+        }
+        let tty = io::stderr().is_terminal();
+        let interval = if tty { TTY_REFRESH } else { Duration::from_secs(config.interval_secs.max(1)) };
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut last = Instant::now();
+            while !stopped.load(Ordering::SeqCst) {
+                thread::sleep(TTY_REFRESH.min(interval));
+                if last.elapsed() < interval {
+                    continue;
+                }
+                last = Instant::now();
+                let snapshot = progress.snapshot();
+                match tty {
+                    true => draw(&snapshot),
+                    false => info!("{}", snapshot.header()),
+                }
+            }
+            erase(&mut io::stderr().lock());
+        });
+        Display { stop, handle: Some(handle) }
+    }
+
+    pub fn stop(mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn draw(snapshot: &Snapshot) {
+    let mut stderr = io::stderr().lock();
+    erase(&mut stderr);
+    let mut lines = vec![snapshot.header()];
+    lines.extend(snapshot.job_lines());
+    for line in &lines {
+        let _ = writeln!(stderr, "{}", line);
+    }
+    *DRAWN.lock().unwrap() = lines.len();
+}
+
+fn erase(stderr: &mut impl Write) {
+    let mut drawn = DRAWN.lock().unwrap();
+    if *drawn > 0 {
+        // Up to the first drawn line and clear to the end of the screen.
+        let _ = write!(stderr, "\x1b[{}A\x1b[J", *drawn);
+        *drawn = 0;
+    }
+}
+
+/// Stderr for the console log, the progress view is erased first and redrawn on the
+/// next refresh, so log lines don't mix with it.
+pub struct StderrWriter;
+
+impl Write for StderrWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stderr = io::stderr().lock();
+        erase(&mut stderr);
+        stderr.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_counting_reader() {
+        let from = Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap();
+        let progress = Arc::new(Progress::new(from, from + chrono::Duration::hours(4)));
+        let id = progress.add("loki app=waf");
+        let guard = progress.start(id);
+
+        let data = "2024-05-01T10:30:00Z first\n{\"ts\":\"2024-05-01 11:00:00\",\"msg\":\"second\"}\nno newline";
+        let mut reader = CountingReader::new(data.as_bytes(), true);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        add_bytes(10);
+
+        let snapshot = progress.snapshot();
+        assert_eq!(snapshot.bytes, data.len() as u64 + 10);
+        assert_eq!(snapshot.lines, 2);
+        assert_eq!(snapshot.running[0].covered, Some(0.25));
+        drop(guard);
+        let snapshot = progress.snapshot();
+        assert_eq!((snapshot.queued, snapshot.running.len(), snapshot.done), (0, 0, 1));
+    }
+}
@@ -1,44 +1,130 @@
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::thread;
-use anyhow::Result;
+use std::time::Duration;
+use anyhow::{Result, Context, bail};
 use r2d2::Pool;
-use std::path::PathBuf;
+use tracing::{debug, error};
 
+use crate::retry;
 use crate::session_manager;
 use crate::ssh_utils;
 use crate::config;
-use crate::constants;
+use crate::k8s_manager;
 
 pub struct PTAFNode {
     host: String,
     port: String,
-    config: config::SharedConfig,
+    reconnect_attempts: u8,
     ssh_manager: ssh_utils::SSHManager,
 }
 
 impl PTAFNode {
     
-    pub fn new(host: String, port: String, config: config::SharedConfig) -> Self {
-        let ssh_manager: ssh_utils::SSHManager = Self::init_ssh_manager(host.clone(), port.clone(), config.clone()).unwrap();
-        PTAFNode { host, port, config, ssh_manager }
+    pub fn new(spec: config::NodeSpec, config: config::SharedConfig) -> Result<Self> {
+        let escalation = Self::escalation(&spec, &config)?;
+        let ssh_manager: ssh_utils::SSHManager = Self::init_ssh_manager(spec.host.clone(), spec.port.to_string(), config.clone())?
+            .with_escalation(escalation);
+        let reconnect_attempts = config.param.ssh.reconnect_attempts;
+        Ok(PTAFNode { host: spec.host, port: spec.port.to_string(), reconnect_attempts, ssh_manager })
     }
 
-    pub fn get_ssh_conn(&self) -> Result<ssh_utils::SSHConnection> {
-        Ok(self.ssh_manager.get_connection()?)
+    /// Every address from config or, if there are none, every InternalIP reported by
+    /// the Kubernetes API.
+    pub fn specs(config: &config::SharedConfig, k8s_manager: Option<&k8s_manager::K8SManager>) -> Result<Vec<config::NodeSpec>> {
+        let nodes_config = &config.param.nodes;
+        let mut specs = nodes_config.static_nodes()?;
+        if specs.is_empty() && nodes_config.discover {
+            let k8s_manager = k8s_manager.context("nodes can't be discovered without the Kubernetes API")?;
+            specs = k8s_manager.get_nodes()?
+                .items
+                .iter()
+                .filter_map(|x| x.internal_ip())
+                .map(|x| nodes_config.node_spec(x.to_string(), nodes_config.port, None, None))
+                .collect();
+        }
+        if specs.is_empty() {
+            bail!("no PTAF nodes configured or discovered");
+        }
+        Ok(specs)
+    }
+
+    /// Builds a node for every spec, see `specs`.
+    pub fn from_config(config: config::SharedConfig, k8s_manager: Option<&k8s_manager::K8SManager>) -> Result<Vec<Arc<PTAFNode>>> {
+        let specs = Self::specs(&config, k8s_manager)?;
+
+        // An unreachable node must not stop harvesting from the rest of the cluster.
+        let mut nodes = vec![];
+        for spec in specs {
+            let addr = format!("{}:{}", spec.host, spec.port);
+            match PTAFNode::new(spec, config.clone()) {
+                Ok(node) => nodes.push(Arc::new(node)),
+                Err(err) => error!(node = %addr, "node is unreachable: {:#}", err),
+            }
+        }
+        if nodes.is_empty() {
+            bail!("none of PTAF nodes is reachable");
+        }
+        Ok(nodes)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.host
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Runs an idempotent operation on a pooled connection, reconnecting when the
+    /// session breaks in the middle. Jobs reconnect under their retry policy instead.
+    pub fn with_ssh_conn<T>(&self, f: impl Fn(&ssh_utils::SSHConnection) -> Result<T>) -> Result<T> {
+        let attempts = match retry::is_active() {
+            true => 1,
+            false => self.reconnect_attempts,
+        };
+        self.ssh_manager.with_retry(attempts, f)
+    }
+
+    fn escalation(spec: &config::NodeSpec, config: &config::SharedConfig) -> Result<Option<ssh_utils::Escalation>> {
+        let escalation = match spec.privilege {
+            config::Privilege::None => None,
+            config::Privilege::SudoNopasswd => Some(ssh_utils::Escalation::SudoNoPasswd),
+            config::Privilege::Sudo => {
+                let password = spec.sudo_password.clone()
+                    .or_else(|| config.param.ssh.password.clone())
+                    .with_context(|| format!("no sudo password for node {}", spec.host))?;
+                Some(ssh_utils::Escalation::Sudo(password))
+            },
+        };
+        Ok(escalation)
     }
 
     fn init_ssh_manager(host: String, port: String, config: config::SharedConfig) -> Result<ssh_utils::SSHManager> {
+        let ssh = &config.param.ssh;
         let manager = session_manager::SessionManager {
-            host: host,
-            port: port,
-            login: config.param.ssh.login.clone(),
-            password: config.param.ssh.password.clone(),
-            key_file: Some(config.param.ssh.key_path()),
+            host,
+            port,
+            login: ssh.login.clone(),
+            password: ssh.password.clone(),
+            key_file: Some(ssh.key_path()),
+            timeouts: session_manager::SessionTimeouts {
+                connect: ssh.connect_timeout,
+                handshake: ssh.handshake_timeout,
+                read: ssh.read_timeout,
+                keepalive_interval: ssh.keepalive_interval,
+            },
         };
-        println!("init ssh manager");
-        // TODO перенести max_size в config
-        let pool = Pool::builder().max_size(10).build(manager)?;
+        debug!(host = %manager.host, "creating ssh pool");
+        let pool_config = &config.param.ssh.pool;
+        let idle_timeout = match pool_config.idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let pool = Pool::builder()
+            .max_size(pool_config.max_size)
+            .min_idle(pool_config.min_idle)
+            .idle_timeout(idle_timeout)
+            .test_on_check_out(pool_config.test_on_check_out)
+            .build(manager)?;
         let ssh_manager = ssh_utils::SSHManager::new(pool);
         Ok(ssh_manager)
     }
@@ -46,9 +132,12 @@ impl PTAFNode {
 }
 
 
+#[cfg(test)]
 mod tests {
+    use std::thread;
 
     use super::*;
+    use crate::constants;
 
     #[test]
     fn test_get_ssh_conn() {
@@ -57,9 +146,10 @@ mod tests {
         cfg.param.ssh.password = Some("admin".to_string());
 
         let shared_config = config::SharedConfig::new(cfg);
+        let spec = shared_config.param.nodes.node_spec("localhost".to_string(), 2222, None, None);
 
         let node = Arc::new(
-            PTAFNode::new("localhost".to_string(), "2222".to_string(), shared_config)
+            PTAFNode::new(spec, shared_config).unwrap()
         );
 
         let threads = vec!["echo 1337", "echo 777"]
@@ -67,16 +157,13 @@ mod tests {
             .map(|x| {
                 let n = node.clone();
                 thread::spawn(move || {
-                    let conn = n.get_ssh_conn().unwrap();
-
-                    let result = conn
-                        .execute(
+                    n
+                        .with_ssh_conn(|conn| conn.execute(
                             x,
                             "".to_string(),
                             None,
-                        )
-                        .unwrap();
-                    result            
+                        ))
+                        .unwrap()
                 })
             });
         
@@ -0,0 +1,245 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use rand::Rng;
+use ssh2::ErrorCode;
+use tracing::{error, warn};
+
+use crate::cancel;
+use crate::config::RetryPolicy;
+use crate::scheduler::Panicked;
+use crate::session_manager::SessionManagerError;
+use crate::ssh_utils::CommandError;
+
+const LIBSSH2_ERROR_AUTHENTICATION_FAILED: i32 = -18;
+const LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED: i32 = -19;
+const LIBSSH2_ERROR_FILE: i32 = -16;
+// r2d2 keeps only the message of the last connection error.
+const AUTH_FAILURE_MESSAGES: [&str; 3] = [
+    "authentication failed",
+    "username/publickey combination invalid",
+    "unable to extract public key",
+];
+// What logcli, curl and friends print on stderr for errors which may go away. Whole
+// phrases, so a file or query mentioning e.g. `502` or `eof` doesn't match.
+const RETRYABLE_STDERR: [&str; 16] = [
+    "429 too many requests",
+    "502 bad gateway",
+    "503 service unavailable",
+    "504 gateway timeout",
+    // curl --fail
+    "returned error: 429",
+    "returned error: 502",
+    "returned error: 503",
+    "returned error: 504",
+    "i/o timeout",
+    "operation timed out",
+    "connection timed out",
+    "context deadline exceeded",
+    "connection refused",
+    "connection reset by peer",
+    "broken pipe",
+    "unexpected eof",
+];
+
+thread_local! {
+    static ACTIVE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Response with a status other than 2xx.
+#[derive(Debug, thiserror::Error)]
+#[error("http status {code}: {body}")]
+pub struct HttpStatusError {
+    pub code: u32,
+    pub body: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorClass {
+    /// Timeouts, throttling, server errors and broken sessions.
+    Retryable,
+    /// Authentication failures, bad queries, panics and the like, another attempt won't help.
+    Fatal,
+}
+
+/// Unknown errors, broken sessions among them, are retryable, so only known
+/// permanent failures stop the retries early.
+pub fn classify(err: &anyhow::Error) -> ErrorClass {
+    err.chain().find_map(classify_cause).unwrap_or(ErrorClass::Retryable)
+}
+
+fn classify_cause(cause: &(dyn std::error::Error + 'static)) -> Option<ErrorClass> {
+    let class = |retryable| if retryable { ErrorClass::Retryable } else { ErrorClass::Fatal };
+    if let Some(err) = cause.downcast_ref::<HttpStatusError>() {
+        return Some(class(err.code >= 500 || err.code == 408 || err.code == 429));
+    }
+    if let Some(err) = cause.downcast_ref::<curl::Error>() {
+        return Some(class(
+            err.is_operation_timedout()
+            || err.is_couldnt_connect()
+            || err.is_couldnt_resolve_host()
+            || err.is_send_error()
+            || err.is_recv_error()
+            || err.is_got_nothing()
+            || err.is_partial_file()
+        ));
+    }
+    if cause.downcast_ref::<Panicked>().is_some() {
+        return Some(ErrorClass::Fatal);
+    }
+    if let Some(err) = cause.downcast_ref::<CommandError>() {
+        let stderr = err.stderr.to_lowercase();
+        return Some(class(RETRYABLE_STDERR.iter().any(|x| stderr.contains(x))));
+    }
+    let ssh_error = match cause.downcast_ref::<SessionManagerError>() {
+        Some(SessionManagerError::SshError(err)) => Some(err),
+        _ => cause.downcast_ref::<ssh2::Error>(),
+    };
+    if let Some(err) = ssh_error {
+        let auth_failed = matches!(
+            err.code(),
+            ErrorCode::Session(
+                LIBSSH2_ERROR_AUTHENTICATION_FAILED
+                | LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED
+                | LIBSSH2_ERROR_FILE
+            )
+        );
+        if auth_failed {
+            return Some(ErrorClass::Fatal);
+        }
+    }
+    if cause.downcast_ref::<r2d2::Error>().is_some() {
+        let message = cause.to_string().to_lowercase();
+        if AUTH_FAILURE_MESSAGES.iter().any(|x| message.contains(x)) {
+            return Some(ErrorClass::Fatal);
+        }
+    }
+    None
+}
+
+/// Delay after the failed `attempt`, counting from 1.
+pub fn delay(policy: &RetryPolicy, attempt: u8) -> Duration {
+    let exponent = attempt.saturating_sub(1) as i32;
+    let delay_ms = (policy.initial_delay_ms as f64 * policy.multiplier.max(1.0).powi(exponent))
+        .min(policy.max_delay_ms as f64);
+    let jitter = policy.jitter.clamp(0.0, 1.0);
+    let factor = if jitter > 0.0 { rand::thread_rng().gen_range(1.0 - jitter..=1.0) } else { 1.0 };
+    Duration::from_millis((delay_ms * factor) as u64)
+}
+
+/// Runs `f` until it succeeds, fails with a fatal error or the policy gives up.
+/// A cancelled run is not retried. Returns the last result and the number of attempts made.
+pub fn run<T>(policy: &RetryPolicy, what: &str, f: impl Fn() -> Result<T>) -> (Result<T>, u8) {
+    let _active = Active(ACTIVE.replace(true));
+    let started = Instant::now();
+    let max_elapsed = Duration::from_secs(policy.max_elapsed_secs);
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let err = match f() {
+            Ok(result) => return (Ok(result), attempt),
+            Err(err) => err,
+        };
+        let delay = delay(policy, attempt);
+        let class = classify(&err);
+        let out_of_time = policy.max_elapsed_secs > 0 && started.elapsed() + delay > max_elapsed;
+        if class == ErrorClass::Fatal || attempt >= max_attempts || out_of_time || cancel::is_cancelled() {
+            error!(attempt, max_attempts, class = ?class, "{} failed: {:#}", what, err);
+            return (Err(err), attempt);
+        }
+        warn!(attempt, max_attempts, delay = ?delay, "{} failed, retrying: {:#}", what, err);
+        cancel::sleep(delay);
+    }
+}
+
+/// Whether the current thread is inside `run`. Its policy covers broken sessions too,
+/// so the operations it runs don't retry on their own.
+pub fn is_active() -> bool {
+    ACTIVE.get()
+}
+
+/// Restores the state of the outer `run`, if any.
+struct Active(bool);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE.set(self.0);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_delay_ms: 1,
+            max_delay_ms: 4,
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_elapsed_secs: 0,
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let err = anyhow::Error::from(HttpStatusError { code: 503, body: String::new() }).context("upload");
+        assert_eq!(classify(&err), ErrorClass::Retryable);
+        let err = anyhow::Error::from(HttpStatusError { code: 401, body: String::new() });
+        assert_eq!(classify(&err), ErrorClass::Fatal);
+
+        let err = anyhow::Error::from(CommandError { status: 1, stderr: "Query failed: 429 Too Many Requests".to_string() });
+        assert_eq!(classify(&err), ErrorClass::Retryable);
+        let err = anyhow::Error::from(CommandError { status: 1, stderr: "parse error at line 1, col 5".to_string() });
+        assert_eq!(classify(&err), ErrorClass::Fatal);
+        let err = anyhow::Error::from(CommandError { status: 22, stderr: "curl: (22) The requested URL returned error: 503".to_string() });
+        assert_eq!(classify(&err), ErrorClass::Retryable);
+        let err = anyhow::Error::from(CommandError { status: 1, stderr: "tail: cannot open '/var/log/eof-502.log': Permission denied".to_string() });
+        assert_eq!(classify(&err), ErrorClass::Fatal);
+
+        let err = anyhow::Error::from(SessionManagerError::SshError(
+            ssh2::Error::new(ErrorCode::Session(LIBSSH2_ERROR_AUTHENTICATION_FAILED), "Authentication failed")
+        ));
+        assert_eq!(classify(&err), ErrorClass::Fatal);
+        assert_eq!(classify(&anyhow::Error::from(SessionManagerError::InvalidSshConnection)), ErrorClass::Retryable);
+        assert_eq!(classify(&anyhow::Error::from(Panicked("index out of bounds".to_string()))), ErrorClass::Fatal);
+    }
+
+    #[test]
+    fn test_delay_and_run() {
+        let mut policy = policy();
+        assert_eq!(delay(&policy, 1), Duration::from_millis(1));
+        assert_eq!(delay(&policy, 3), Duration::from_millis(4));
+        assert_eq!(delay(&policy, 10), Duration::from_millis(4));
+        policy.jitter = 0.5;
+        policy.initial_delay_ms = 1000;
+        policy.max_delay_ms = 1000;
+        assert!((500..=1000).contains(&(delay(&policy, 1).as_millis() as u64)));
+
+        let policy = self::policy();
+        let calls = AtomicU8::new(0);
+        let (result, attempts) = run(&policy, "test", || {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(HttpStatusError { code: 500, body: String::new() }.into()),
+                _ => Ok(42),
+            }
+        });
+        assert_eq!((result.unwrap(), attempts), (42, 3));
+
+        let (result, attempts) = run(&policy, "test", || -> Result<()> {
+            Err(HttpStatusError { code: 400, body: String::new() }.into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        assert!(!is_active());
+        let (result, _) = run(&policy, "test", || Ok(is_active()));
+        assert!(result.unwrap());
+        assert!(!is_active());
+    }
+}
@@ -0,0 +1,186 @@
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use anyhow::Result;
+use tracing::error;
+
+use crate::cancel;
+use crate::progress::Progress;
+
+/// Unit of work for the scheduler. Jobs with the same target share its concurrency limit.
+pub struct Job {
+    pub target: String,
+    pub name: String,
+    run: Box<dyn FnOnce() + Send>,
+    /// Id in the progress of the run.
+    progress_id: Option<usize>,
+}
+
+impl Job {
+    pub fn new(target: &str, name: String, run: impl FnOnce() + Send + 'static) -> Self {
+        Job { target: target.to_string(), name, run: Box::new(run), progress_id: None }
+    }
+}
+
+/// A job panicked, with the message of the panic.
+#[derive(Debug, thiserror::Error)]
+#[error("panicked: {0}")]
+pub struct Panicked(pub String);
+
+/// Runs `f`, a panic in it becomes `Panicked`, so the job still gets an outcome.
+pub fn catch_panic<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| Err(Panicked(panic_message(&*payload)).into()))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    running: HashMap<String, usize>,
+}
+
+/// Runs jobs on a fixed number of worker threads. A job waits while its target is
+/// at the limit, jobs for other targets behind it go first.
+pub struct Scheduler {
+    max_jobs: usize,
+    limits: HashMap<String, usize>,
+    job_timeout: Duration,
+    progress: Option<Arc<Progress>>,
+}
+
+impl Scheduler {
+
+    pub fn new(max_jobs: usize) -> Self {
+        Scheduler { max_jobs: max_jobs.max(1), limits: HashMap::new(), job_timeout: Duration::ZERO, progress: None }
+    }
+
+    /// Jobs of a target without a limit are bound by `max_jobs` only.
+    pub fn limit(mut self, target: &str, max_jobs: usize) -> Self {
+        self.limits.insert(target.to_string(), max_jobs.max(1));
+        self
+    }
+
+    /// A job running longer is cancelled, see `cancel::job_timeout`.
+    pub fn job_timeout(mut self, timeout: Duration) -> Self {
+        self.job_timeout = timeout;
+        self
+    }
+
+    /// Jobs are tracked from queued to done.
+    pub fn progress(mut self, progress: Arc<Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Blocks until every job is done. Once the run is cancelled no more jobs start,
+    /// the ones left are returned.
+    pub fn run(&self, mut jobs: Vec<Job>) -> Vec<Job> {
+        if let Some(progress) = &self.progress {
+            for job in &mut jobs {
+                job.progress_id = Some(progress.add(&job.name));
+            }
+        }
+        let workers = self.max_jobs.min(jobs.len());
+        let queue = Mutex::new(Queue { jobs: jobs.into(), running: HashMap::new() });
+        let ready = Condvar::new();
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| self.worker(&queue, &ready));
+            }
+        });
+        queue.into_inner().unwrap().jobs.into()
+    }
+
+    fn worker(&self, queue: &Mutex<Queue>, ready: &Condvar) {
+        let mut state = queue.lock().unwrap();
+        loop {
+            if state.jobs.is_empty() || cancel::is_cancelled() {
+                return;
+            }
+            let Some(job) = self.next(&mut state) else {
+                state = ready.wait(state).unwrap();
+                continue;
+            };
+            *state.running.entry(job.target.clone()).or_default() += 1;
+            drop(state);
+
+            let target = job.target;
+            let name = job.name;
+            let guard = self.progress.as_ref().zip(job.progress_id).map(|(progress, id)| progress.start(id));
+            let deadline = cancel::job_timeout(self.job_timeout);
+            // Jobs record their own outcome with `catch_panic`, this keeps the worker alive.
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job.run)) {
+                error!(job = %name, "job panicked: {}", panic_message(&*payload));
+            }
+            drop(deadline);
+            drop(guard);
+
+            state = queue.lock().unwrap();
+            if let Some(running) = state.running.get_mut(&target) {
+                *running -= 1;
+            }
+            ready.notify_all();
+        }
+    }
+
+    fn next(&self, state: &mut Queue) -> Option<Job> {
+        let index = state.jobs.iter().position(|job| {
+            let running = state.running.get(&job.target).copied().unwrap_or(0);
+            self.limits.get(&job.target).is_none_or(|limit| running < *limit)
+        })?;
+        state.jobs.remove(index)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        let mut jobs = vec![];
+        for i in 0..12 {
+            let target = if i % 3 == 0 { "node" } else { "loki" };
+            let (running, max_running, done) = (running.clone(), max_running.clone(), done.clone());
+            jobs.push(Job::new(target, format!("job {}", i), move || {
+                if target == "loki" {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }
+                done.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        Scheduler::new(4).limit("loki", 2).run(jobs);
+
+        assert_eq!(done.load(Ordering::SeqCst), 12);
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic(|| Ok(1)).unwrap(), 1);
+        let err = catch_panic(|| -> Result<()> { panic!("index {} out of range", 3) }).unwrap_err();
+        assert_eq!(err.to_string(), "panicked: index 3 out of range");
+        let err = catch_panic(|| -> Result<()> { panic!("no records") }).unwrap_err();
+        assert!(err.downcast_ref::<Panicked>().is_some());
+    }
+}
@@ -1,9 +1,17 @@
-use std::{net::TcpStream, io::Read, path::PathBuf};
+use std::{net::{TcpStream, ToSocketAddrs}, io, path::PathBuf, time::Duration};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use r2d2::ManageConnection;
-use ssh2::Session;
+use ssh2::{ErrorCode, Session};
 use thiserror::Error;
-// use std::fs;
-use std::path;
+use tracing::debug;
+
+// libssh2 error codes which mean the transport is gone and the session has to be recreated.
+const LIBSSH2_ERROR_SOCKET_SEND: i32 = -7;
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_SOCKET_TIMEOUT: i32 = -30;
+const LIBSSH2_ERROR_SOCKET_RECV: i32 = -43;
 
 #[derive(Debug, Error)]
 pub enum SessionManagerError {
@@ -17,65 +25,157 @@ pub enum SessionManagerError {
     InvalidSshConnection,
 }
 
+/// Limits applied to every session of the pool, in seconds. Zero disables a limit.
+#[derive(Clone, Debug)]
+pub struct SessionTimeouts {
+    pub connect: u64,
+    pub handshake: u64,
+    pub read: u64,
+    pub keepalive_interval: u32,
+}
+
+impl Default for SessionTimeouts {
+    fn default() -> Self {
+        SessionTimeouts { connect: 10, handshake: 30, read: 300, keepalive_interval: 30 }
+    }
+}
+
+/// Pooled SSH session which remembers that its transport has failed, so the pool
+/// can drop it without probing the node again.
+pub struct PooledSession {
+    session: Session,
+    broken: AtomicBool,
+}
+
+impl PooledSession {
+    pub fn mark_broken(&self) {
+        self.broken.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Deref for PooledSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        &self.session
+    }
+}
+
 pub struct SessionManager {
     pub host: String,
     pub port: String,
     pub login: String,
     pub password: Option<String>,
     pub key_file: Option<String>,
+    pub timeouts: SessionTimeouts,
+}
+
+impl SessionManager {
+    fn tcp_connect(&self) -> Result<TcpStream, SessionManagerError> {
+        let addr = format!("{}:{}", self.host, self.port);
+        if self.timeouts.connect == 0 {
+            return Ok(TcpStream::connect(addr)?);
+        }
+        let timeout = Duration::from_secs(self.timeouts.connect);
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, format!("no address resolved for {}", addr));
+        for socket_addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err.into())
+    }
+}
+
+/// Tells whether the error means the SSH transport is broken, so an idempotent
+/// operation may be repeated on a new session.
+pub fn is_broken_session(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<ssh2::Error>() {
+            return matches!(
+                err.code(),
+                ErrorCode::Session(
+                    LIBSSH2_ERROR_SOCKET_SEND
+                    | LIBSSH2_ERROR_TIMEOUT
+                    | LIBSSH2_ERROR_SOCKET_DISCONNECT
+                    | LIBSSH2_ERROR_SOCKET_TIMEOUT
+                    | LIBSSH2_ERROR_SOCKET_RECV
+                )
+            );
+        }
+        if let Some(err) = cause.downcast_ref::<io::Error>() {
+            return matches!(
+                err.kind(),
+                io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+            );
+        }
+        if let Some(err) = cause.downcast_ref::<SessionManagerError>() {
+            return match err {
+                SessionManagerError::TcpError(_) | SessionManagerError::InvalidSshConnection => true,
+                SessionManagerError::SshError(_) => false,
+            };
+        }
+        cause.downcast_ref::<r2d2::Error>().is_some()
+    })
 }
 
 impl ManageConnection for SessionManager {
-    type Connection = Session;
+    type Connection = PooledSession;
     type Error = SessionManagerError;
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        let tcp_stream = TcpStream::connect(
-            format!("{}:{}", self.host, self.port)
-        )
-            .map_err(|err| SessionManagerError::from(err))?;
+        let tcp_stream = self.tcp_connect()?;
         
-        let mut session = Session::new()
-            .map_err(|err| SessionManagerError::from(err))?;
+        let mut session = Session::new()?;
         
         session.set_tcp_stream(tcp_stream);
+        session.set_timeout((self.timeouts.handshake * 1000) as u32);
         session.handshake()?;
 
         if let Some(passw) = &self.password {
-            session.userauth_password(&self.login, &passw)?;
+            session.userauth_password(&self.login, passw)?;
         } else if let Some(key_file) = &self.key_file {
             let path = PathBuf::from(key_file);
-            println!("get userauth_pubkey_file");
+            debug!(key_file, "public key authentication");
             session.userauth_pubkey_file(&self.login, None, &path, None)?;
-            println!("end userauth_pubkey_file");
         }
 
-        Ok(session)
+        session.set_timeout((self.timeouts.read * 1000) as u32);
+        if self.timeouts.keepalive_interval > 0 {
+            session.set_keepalive(false, self.timeouts.keepalive_interval);
+        }
+
+        Ok(PooledSession { session, broken: AtomicBool::new(false) })
     }
 
+    /// Opens and closes a channel, which waits for the node to answer. A keepalive
+    /// is not enough, it isn't sent at all before its interval has passed.
     fn is_valid(&self, conn: &mut Self::Connection) -> std::result::Result<(), Self::Error> {
-        let mut channel = conn.channel_session()
-            .map_err(|err| SessionManagerError::from(err))?;
-        channel.exec("echo TEST")?;
-        
-        let mut buf = String::new();
-        channel.read_to_string(&mut buf)
-            .map_err(|err| SessionManagerError::from(err))?;
-        
-        if buf.is_empty() {
-            Err(SessionManagerError::InvalidSshConnection)
-        } else {
-            Ok(())
+        if conn.broken.load(Ordering::Relaxed) {
+            return Err(SessionManagerError::InvalidSshConnection);
         }
+        conn.channel_session().and_then(|mut channel| channel.close()).map_err(|err| {
+            conn.mark_broken();
+            SessionManagerError::from(err)
+        })?;
+        Ok(())
     }
 
+    /// Called on every return to the pool, so only the local flag is checked here.
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        self.is_valid(conn).is_err()
+        conn.broken.load(Ordering::Relaxed)
     }
 
 }
 
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -87,6 +187,7 @@ mod tests {
             login: "admin".to_string(),
             password: Some("admin".to_string()),
             key_file: None,
+            timeouts: SessionTimeouts::default(),
         };
         let result = session_manager.connect();
         assert!(result.is_ok());
@@ -100,9 +201,22 @@ mod tests {
             login: "admin".to_string(),
             password: Some("admin".to_string()),
             key_file: None,
+            timeouts: SessionTimeouts::default(),
         };
         let mut connection = session_manager.connect().unwrap();
         let result = session_manager.is_valid(&mut connection);
         assert!(result.is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_is_broken_session() {
+        let timeout = anyhow::Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert!(is_broken_session(&timeout.context("read failed")));
+
+        let auth = anyhow::Error::from(ssh2::Error::from_errno(ErrorCode::Session(-18)));
+        assert!(!is_broken_session(&auth));
+
+        let disconnect = anyhow::Error::from(ssh2::Error::from_errno(ErrorCode::Session(LIBSSH2_ERROR_SOCKET_DISCONNECT)));
+        assert!(is_broken_session(&disconnect));
+    }
+}
@@ -1,48 +1,115 @@
-use crate::session_manager::SessionManager;
+use crate::session_manager::{self, SessionManager};
+use crate::cancel;
+use crate::progress;
+use crate::redact;
 use r2d2::{Pool, PooledConnection};
-use std::collections::HashMap;
-use std::io::{Read, ErrorKind, Write};
+use ssh2::Channel;
+use sha2::{Digest, Sha256};
+use std::io::{Read, ErrorKind, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::fs;
-use std::sync::Mutex;
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use tracing::{debug, info, warn};
+
+// Only the tail of stderr goes to the error, it usually holds the reason.
+const STDERR_TAIL_SIZE: usize = 2048;
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+// First line of stderr of a stream, the pid is used to stop the command on cancellation.
+const PID_MARKER: &str = "harvester-pid";
+
+/// Privilege escalation applied to every remote command of a node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Escalation {
+    /// `sudo -k -S`, the password is written to stdin of the channel. Cached credentials
+    /// are ignored, otherwise the password would become stdin of the command.
+    Sudo(String),
+    /// `sudo -n`, fails instead of asking for a password.
+    SudoNoPasswd,
+}
+
+impl Escalation {
+    fn wrap(&self, command: &str) -> String {
+        match self {
+            Escalation::Sudo(_) => format!("sudo -k -S -p '' -- sh -c {}", shell_quote(command)),
+            Escalation::SudoNoPasswd => format!("sudo -n -- sh -c {}", shell_quote(command)),
+        }
+    }
+}
 
 pub struct SSHConnection {
-    connection: PooledConnection<SessionManager>
+    connection: PooledConnection<SessionManager>,
+    escalation: Option<Escalation>,
 }
 
 impl SSHConnection {
 
+    /// Starts the command with escalation of the node applied. With a sudo password
+    /// no pty is requested, otherwise the password written to stdin would be echoed
+    /// back into the output. Binary output must not go through a pty either.
+    /// `input` goes to stdin of the command, after the sudo password if there is one.
+    fn exec_channel(&self, command: &str, pty: bool, input: &str) -> Result<Channel> {
+        let mut channel = self.connection.channel_session()?;
+        let command = match &self.escalation {
+            Some(escalation) => escalation.wrap(command),
+            None => command.to_string(),
+        };
+        if pty && !matches!(self.escalation, Some(Escalation::Sudo(_))) {
+            channel.request_pty_size(1024, 24, Some(0), Some(0))?;
+        }
+        debug!(command = %redact::mask_command(&command), "exec");
+        channel.exec(&command)?;
+        if let Some(Escalation::Sudo(password)) = &self.escalation {
+            channel.write_all(format!("{}\n", password).as_bytes())?;
+        }
+        if !input.is_empty() || matches!(self.escalation, Some(Escalation::Sudo(_))) {
+            channel.write_all(input.as_bytes())?;
+            channel.send_eof()?;
+        }
+        Ok(channel)
+    }
+
+    /// Output lines of the command. A non-zero exit status, e.g. of a refused `sudo`,
+    /// is a `CommandError`, commands which may fail harmlessly have to say so themselves.
     pub fn execute(
         &self,
         command: &str,
         envs: String,
         working_directory: Option<&str>,
     ) -> Result<Vec<String>> {
-        println!("get session");
-        let mut channel = self.connection.channel_session()?;
+        cancel::check()?;
         let mut command = match working_directory {
             Some(dir) => format!("cd {}; {}", dir, command),
             None => command.to_string(),
         };
-        if envs.len() > 0 {
+        if !envs.is_empty() {
             command = format!("{}; {}", envs, command);
         }
 
-        channel.request_pty_size(1024, 24, Some(0), Some(0))?;
-        println!("command execute: {}", command);
-        channel.exec(&command)?;
+        let mut channel = self.exec_channel(&command, true, "")?;
 
         let mut buf = vec![0; 1024];
         let mut chunks = Vec::new();
-        
-        println!("trying to get data");
-        while let Ok(bytes_read) = channel.read(&mut buf) {
+
+        loop {
+            let bytes_read = channel.read(&mut buf)?;
             if bytes_read == 0 {
                 break;
             }
-            let chunk = &buf[..bytes_read];
-            chunks.extend(chunk.to_owned());
+            chunks.extend_from_slice(&buf[..bytes_read]);
+            // Sends a keepalive only when the configured interval has passed.
+            self.connection.keepalive_send()?;
+        }
+
+        let mut stderr = vec![];
+        channel.stderr().read_to_end(&mut stderr)?;
+        channel.wait_close()?;
+        let status = channel.exit_status()?;
+        if status != 0 {
+            // A pty merges stderr into stdout.
+            let output = if stderr.is_empty() { &chunks } else { &stderr };
+            let tail = &output[output.len().saturating_sub(STDERR_TAIL_SIZE)..];
+            let stderr = String::from_utf8_lossy(tail).trim().to_string();
+            return Err(CommandError { status, stderr }.into());
         }
 
         let res = String::from_utf8_lossy(&chunks);
@@ -51,68 +118,262 @@ impl SSHConnection {
             .filter(|x| !x.is_empty())
             .map(|x| x.to_string())
             .collect();
-        channel.close()?;
         Ok(splited)
     }
 
+    /// Downloads into `<destination_file>.part` and renames it when done. A `.part`
+    /// left by a broken connection is resumed from its size, one left by a failed
+    /// remote read is removed. With `verify_checksum` the
+    /// result is compared with `sha256sum` of the remote file before the rename.
     pub fn copy_to_local(
         &self,
         source: &str,
         destination_file: &str,
+        verify_checksum: bool,
     ) -> Result<()> {
-        println!("dest file: {}", destination_file);
         let dirname = Path::new(destination_file)
             .parent()
             .ok_or(std::io::Error::from(ErrorKind::InvalidInput))?
             .to_str()
             .ok_or(std::io::Error::from(ErrorKind::InvalidData))?;
         ensure_dir_exists(dirname)?;
-        println!("dirname: {}", dirname);
 
-        let sftp = self.connection.sftp()?;
+        let part_file = format!("{}.part", destination_file);
+        let offset = fs::metadata(&part_file).map(|x| x.len()).unwrap_or(0);
+        if offset > 0 {
+            info!(source, offset, "resuming copy");
+        }
+
+        debug!(source, dest = destination_file, "copy started");
+        // SFTP runs with rights of the login user, so escalated reads go through `tail`.
+        match &self.escalation {
+            Some(_) => {
+                let mut stream = self.open_stream(&format!("tail -c +{} -- {}", offset + 1, shell_quote(source)))?;
+                self.append(&mut stream, &part_file)?;
+                // A failed `tail` leaves a truncated or empty `.part`, it can't be resumed.
+                if let Err(err) = stream.finish(&[0]) {
+                    fs::remove_file(&part_file)?;
+                    return Err(err.context(format!("failed to read {}", source)));
+                }
+            },
+            None => {
+                let mut file = self.connection.sftp()?.open(Path::new(source))?;
+                file.seek(SeekFrom::Start(offset))?;
+                self.append(&mut file, &part_file)?;
+            },
+        }
 
-        let mut file = sftp.open(Path::new(source))?;
+        if verify_checksum {
+            self.verify_checksum(source, &part_file)?;
+        }
+        fs::rename(&part_file, destination_file)?;
+        Ok(())
+    }
 
-        let mut buf = vec![0; 20 * 1024 * 1024];
-        let mut destination_file = fs::File::create(destination_file)?;
-        println!("start loop: {}", dirname);
+    fn append(&self, mut reader: impl Read, part_file: &str) -> Result<()> {
+        let mut buf = vec![0; COPY_BUFFER_SIZE];
+        let mut part = fs::OpenOptions::new().create(true).append(true).open(part_file)?;
         loop {
-            let bytes_read = file.read(&mut buf)?;
+            cancel::check()?;
+            let bytes_read = reader.read(&mut buf)?;
             if bytes_read == 0 {
                 break;
             }
-            destination_file.write_all(&buf[..bytes_read])?;
+            part.write_all(&buf[..bytes_read])?;
+            progress::add_bytes(bytes_read as u64);
+            self.connection.keepalive_send()?;
+        }
+        part.sync_all()?;
+        debug!(file = part_file, size = part.metadata()?.len(), "copy done");
+        Ok(())
+    }
+
+    /// A mismatching `.part` is removed, so the next attempt starts from zero. A failed
+    /// `sha256sum` is an error of its own and keeps the `.part`.
+    fn verify_checksum(&self, source: &str, local_file: &str) -> Result<()> {
+        let remote = self.sha256(source)?;
+        let local = sha256_file(local_file)?;
+        if remote != local {
+            fs::remove_file(local_file)?;
+            bail!("checksum mismatch of {}: remote {:?}, local {}", source, remote, local);
         }
-        println!("end loop: {}", dirname);
         Ok(())
     }
+
+    /// Hex encoded SHA-256 of a remote file, from `sha256sum`.
+    pub fn sha256(&self, source: &str) -> Result<String> {
+        let output = self
+            .output(&format!("sha256sum -- {}", shell_quote(source)))
+            .with_context(|| format!("failed to checksum {}", source))?;
+        Ok(output.split_whitespace().next().unwrap_or_default().to_string())
+    }
+
+    /// Raw stdout of the command, bytes are passed as is.
+    pub fn open_stream(&self, command: &str) -> Result<RemoteStream<'_>> {
+        self.open_stream_with_input(command, "")
+    }
+
+    /// Like `open_stream`, `input` is written to stdin of the command, e.g. secrets which
+    /// must not show up on its command line.
+    pub fn open_stream_with_input(&self, command: &str, input: &str) -> Result<RemoteStream<'_>> {
+        cancel::check()?;
+        // Closing the channel doesn't stop a command without a pty, so it reports its pid.
+        let mut channel = self.exec_channel(&format!("echo {} $$ >&2; {}", PID_MARKER, command), false, input)?;
+        let pid = read_pid(&mut channel)?;
+        Ok(RemoteStream { channel, conn: self, pid })
+    }
+
+    /// Stdout of the command. Unlike `execute` a non-zero exit status is an error.
+    pub fn output(&self, command: &str) -> Result<String> {
+        self.output_with_input(command, "")
+    }
+
+    /// Stdout of the command with `input` on its stdin, see `open_stream_with_input`.
+    pub fn output_with_input(&self, command: &str, input: &str) -> Result<String> {
+        let mut stream = self.open_stream_with_input(command, input)?;
+        let mut stdout = String::new();
+        stream.read_to_string(&mut stdout)?;
+        stream.finish(&[0])?;
+        Ok(stdout)
+    }
+}
+
+/// Remote command exited with an unexpected status.
+#[derive(Debug, thiserror::Error)]
+#[error("exit status {status}: {stderr}")]
+pub struct CommandError {
+    pub status: i32,
+    pub stderr: String,
+}
+
+/// Stdout of a remote command which keeps the session alive while it is read.
+pub struct RemoteStream<'a> {
+    channel: Channel,
+    conn: &'a SSHConnection,
+    pid: Option<u32>,
+}
+
+impl RemoteStream<'_> {
+    /// Stops the shell running the command and its children, e.g. `logcli | gzip`.
+    fn kill(&self) {
+        let Some(pid) = self.pid else {
+            return;
+        };
+        let result = self.conn
+            .exec_channel(&format!("pkill -TERM -P {pid}; kill -TERM {pid}", pid = pid), false, "")
+            .and_then(|mut channel| Ok(channel.wait_close()?));
+        if let Err(err) = result {
+            warn!(pid, "failed to stop remote command: {:#}", err);
+        }
+    }
+
+    /// Waits for the command to exit and returns its exit status. Statuses other
+    /// than `ok_statuses` become `CommandError` with the tail of stderr.
+    pub fn finish(mut self, ok_statuses: &[i32]) -> Result<i32> {
+        let mut stderr = vec![];
+        self.channel.stderr().read_to_end(&mut stderr)?;
+        self.channel.wait_close()?;
+        let status = self.channel.exit_status()?;
+        if !ok_statuses.contains(&status) {
+            let tail = &stderr[stderr.len().saturating_sub(STDERR_TAIL_SIZE)..];
+            let stderr = String::from_utf8_lossy(tail).trim().to_string();
+            return Err(CommandError { status, stderr }.into());
+        }
+        Ok(status)
+    }
+}
+
+impl Read for RemoteStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(reason) = cancel::cancelled() {
+            self.kill();
+            return Err(std::io::Error::other(reason));
+        }
+        let bytes_read = self.channel.read(buf)?;
+        self.conn.connection.keepalive_send()?;
+        Ok(bytes_read)
+    }
+}
+
+fn read_pid(channel: &mut Channel) -> Result<Option<u32>> {
+    let mut line = vec![];
+    let mut byte = [0; 1];
+    while channel.stderr().read(&mut byte)? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8_lossy(&line);
+    Ok(line.strip_prefix(PID_MARKER).and_then(|x| x.trim().parse().ok()))
+}
+
+/// Hex encoded SHA-256 of a local file.
+pub fn sha256_file(path: &str) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Quotes a string for POSIX shell, e.g. `it's` -> `'it'\''s'`.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
 }
 
 pub struct SSHManager{
-    pool: Mutex<Pool<SessionManager>>,
+    pool: Pool<SessionManager>,
+    escalation: Option<Escalation>,
 }
 
 impl SSHManager {
     pub fn new(pool: Pool<SessionManager>) -> SSHManager {
-        SSHManager { pool: Mutex::new(pool) }
+        SSHManager { pool, escalation: None }
+    }
+
+    pub fn with_escalation(mut self, escalation: Option<Escalation>) -> Self {
+        self.escalation = escalation;
+        self
     }
 
     pub fn get_connection(&self) -> Result<SSHConnection> {
-        let pool = self.pool.lock().unwrap();
-        let conn = pool.get()?;
-        Ok(SSHConnection{connection: conn})
+        let conn = self.pool.get()?;
+        Ok(SSHConnection{connection: conn, escalation: self.escalation.clone()})
+    }
+
+    /// Runs an idempotent operation, repeating it on a fresh session up to `attempts`
+    /// times in total when the session breaks. Broken sessions are marked, so the pool
+    /// drops them on return.
+    pub fn with_retry<T>(&self, attempts: u8, f: impl Fn(&SSHConnection) -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            let result = self.get_connection().and_then(|conn| {
+                let result = f(&conn);
+                if let Err(err) = &result {
+                    if session_manager::is_broken_session(err) {
+                        conn.connection.mark_broken();
+                    }
+                }
+                result
+            });
+            match result {
+                Err(err) if attempt < attempts && session_manager::is_broken_session(&err) => {
+                    warn!(attempt, "broken ssh session, reconnecting: {:#}", err);
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
     }
 }
 
 fn ensure_dir_exists(dir_path: &str) -> Result<()> {
     let path = Path::new(dir_path);
     if !path.exists() {
-        fs::create_dir(path)?;
+        fs::create_dir_all(path)?;
     }
     Ok(())
 }
 
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -125,6 +386,7 @@ mod tests {
             login: login.clone(),
             password: Some("admin".to_string()),
             key_file: None,
+            timeouts: session_manager::SessionTimeouts::default(),
         };
 
         let pool = Pool::builder()
@@ -145,5 +407,36 @@ mod tests {
             )
             .unwrap();
         assert_eq!(result, vec![msg, "1"]);
+        let err = conn.execute("echo denied; exit 1", String::new(), None).unwrap_err();
+        assert_eq!(err.downcast_ref::<CommandError>().unwrap().status, 1);
+
+        assert_eq!(conn.output("echo ok").unwrap(), "ok\n");
+        assert_eq!(conn.output_with_input("read -r x && echo \"$x\"", "secret\n").unwrap(), "secret\n");
+        let err = conn.output("echo 'error: 401 Unauthorized' >&2; exit 1").unwrap_err();
+        let err = err.downcast_ref::<CommandError>().unwrap();
+        assert_eq!((err.status, err.stderr.as_str()), (1, "error: 401 Unauthorized"));
+    }
+
+    #[test]
+    fn test_escalation_wrap() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(
+            Escalation::SudoNoPasswd.wrap("cat '/var/log/a b'"),
+            r"sudo -n -- sh -c 'cat '\''/var/log/a b'\'''",
+        );
+        let sudo = Escalation::Sudo("secret".to_string()).wrap("journalctl -u kubelet");
+        assert_eq!(sudo, "sudo -k -S -p '' -- sh -c 'journalctl -u kubelet'");
+        assert!(!sudo.contains("secret"));
+    }
+
+    #[test]
+    fn test_sha256_file() {
+        let path = std::env::temp_dir().join("harvester_test_sha256_file");
+        fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            sha256_file(path.to_str().unwrap()).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        );
+        fs::remove_file(path).unwrap();
     }
 }
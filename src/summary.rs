@@ -0,0 +1,206 @@
+use std::time::Duration;
+use serde::Serialize;
+
+use crate::bundle::{ArtifactRecord, ArtifactStatus};
+use crate::cancel;
+
+/// Every job ended `ok` or `skipped`.
+pub const EXIT_OK: i32 = 0;
+/// The run could not produce a bundle.
+pub const EXIT_FAILED: i32 = 1;
+/// The bundle is there, but some jobs or the upload failed.
+pub const EXIT_PARTIAL: i32 = 2;
+/// The run was cancelled, the bundle holds what was collected until then.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Ok,
+    /// Some items of the job failed, e.g. one of the files.
+    Partial,
+    Failed,
+    /// Nothing to collect, e.g. no core dumps in the time window.
+    Skipped,
+    /// The job never ran because the run was interrupted or past its deadline.
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JobOutcome {
+    pub job: String,
+    pub node: Option<String>,
+    pub status: JobStatus,
+    pub attempts: u8,
+    pub duration_secs: f64,
+    pub bytes: u64,
+    pub lines: u64,
+    /// Error chain of the last attempt or of the first failed item.
+    pub error: Option<String>,
+}
+
+impl JobOutcome {
+
+    pub fn from_records(job: &str, node: Option<&str>, attempts: u8, duration: Duration, records: &[ArtifactRecord]) -> Self {
+        let count = |status| records.iter().filter(|x| x.status == status).count();
+        let status = match (count(ArtifactStatus::Ok), count(ArtifactStatus::Failed)) {
+            _ if records.is_empty() => JobStatus::Skipped,
+            (ok, _) if ok == records.len() => JobStatus::Ok,
+            (_, failed) if failed == records.len() => JobStatus::Failed,
+            _ => JobStatus::Partial,
+        };
+        JobOutcome {
+            job: job.to_string(),
+            node: node.map(|x| x.to_string()),
+            status,
+            attempts,
+            duration_secs: duration.as_secs_f64(),
+            bytes: records.iter().map(|x| x.size).sum(),
+            lines: records.iter().filter_map(|x| x.lines).sum(),
+            error: records.iter().find_map(|x| x.error.clone()),
+        }
+    }
+
+    pub fn failed(job: &str, node: Option<&str>, attempts: u8, duration: Duration, err: &anyhow::Error) -> Self {
+        JobOutcome {
+            job: job.to_string(),
+            node: node.map(|x| x.to_string()),
+            status: JobStatus::Failed,
+            attempts,
+            duration_secs: duration.as_secs_f64(),
+            bytes: 0,
+            lines: 0,
+            error: Some(format!("{:#}", err)),
+        }
+    }
+
+    pub fn cancelled(job: &str, node: Option<&str>, reason: cancel::Cancelled) -> Self {
+        JobOutcome {
+            job: job.to_string(),
+            node: node.map(|x| x.to_string()),
+            status: JobStatus::Cancelled,
+            attempts: 0,
+            duration_secs: 0.0,
+            bytes: 0,
+            lines: 0,
+            error: Some(reason.to_string()),
+        }
+    }
+}
+
+pub fn exit_code(jobs: &[JobOutcome]) -> i32 {
+    let ok = |x: &JobOutcome| matches!(x.status, JobStatus::Ok | JobStatus::Skipped);
+    if jobs.iter().all(ok) { EXIT_OK } else { EXIT_PARTIAL }
+}
+
+/// One line per job, failed ones first, and the totals.
+pub fn table(jobs: &[JobOutcome]) -> String {
+    let mut jobs = jobs.to_vec();
+    jobs.sort_by_key(|x| (status_order(x.status), x.node.clone(), x.job.clone()));
+
+    let mut rows = vec![];
+    for job in &jobs {
+        rows.push([
+            format!("{:?}", job.status).to_lowercase(),
+            job.node.clone().unwrap_or("-".to_string()),
+            job.job.clone(),
+            job.attempts.to_string(),
+            format!("{:.1}s", job.duration_secs),
+            job.bytes.to_string(),
+            job.lines.to_string(),
+            job.error.clone().unwrap_or_default().replace('\n', " "),
+        ]);
+    }
+    let mut result = render_table(["STATUS", "NODE", "JOB", "TRIES", "TIME", "BYTES", "LINES", "ERROR"], &rows);
+    let count = |status| jobs.iter().filter(|x| x.status == status).count();
+    result.push_str(&format!(
+        "\njobs: {}, ok: {}, partial: {}, failed: {}, skipped: {}, cancelled: {}, bytes: {}, lines: {}\n",
+        jobs.len(),
+        count(JobStatus::Ok),
+        count(JobStatus::Partial),
+        count(JobStatus::Failed),
+        count(JobStatus::Skipped),
+        count(JobStatus::Cancelled),
+        jobs.iter().map(|x| x.bytes).sum::<u64>(),
+        jobs.iter().map(|x| x.lines).sum::<u64>(),
+    ));
+    result
+}
+
+/// Columns aligned to the widest cell, the last one is not padded.
+pub fn render_table<const N: usize>(headers: [&str; N], rows: &[[String; N]]) -> String {
+    let headers = headers.map(String::from);
+    let rows = std::iter::once(&headers).chain(rows).collect::<Vec<_>>();
+    let widths = (0..N.saturating_sub(1))
+        .map(|i| rows.iter().map(|x| x[i].chars().count()).max().unwrap_or(0))
+        .collect::<Vec<_>>();
+
+    let mut result = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(&widths) {
+            line.push_str(&format!("{:<width$}  ", cell, width = width));
+        }
+        line.push_str(row.last().map_or("", |x| x.as_str()));
+        result.push_str(line.trim_end());
+        result.push('\n');
+    }
+    result
+}
+
+fn status_order(status: JobStatus) -> u8 {
+    match status {
+        JobStatus::Failed => 0,
+        JobStatus::Partial => 1,
+        JobStatus::Cancelled => 2,
+        JobStatus::Skipped => 3,
+        JobStatus::Ok => 4,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(status: ArtifactStatus, size: u64) -> ArtifactRecord {
+        ArtifactRecord {
+            path: None,
+            source: "file /var/log/messages".to_string(),
+            node: None,
+            size,
+            lines: Some(1),
+            sha256: None,
+            error: (status == ArtifactStatus::Failed).then(|| "no such file".to_string()),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_outcome_and_table() {
+        let duration = Duration::from_millis(1500);
+        let ok = JobOutcome::from_records("journal kubelet.service", Some("node-1"), 1, duration, &[record(ArtifactStatus::Ok, 10)]);
+        let partial = JobOutcome::from_records(
+            "files /var/log/*", Some("node-1"), 2, duration,
+            &[record(ArtifactStatus::Ok, 5), record(ArtifactStatus::Failed, 0)],
+        );
+        let skipped = JobOutcome::from_records("cores /var/lib/systemd/coredump", None, 1, duration, &[]);
+        assert_eq!(ok.status, JobStatus::Ok);
+        assert_eq!(partial.status, JobStatus::Partial);
+        assert_eq!(partial.error.as_deref(), Some("no such file"));
+        assert_eq!(skipped.status, JobStatus::Skipped);
+        assert_eq!(exit_code(&[ok.clone(), skipped.clone()]), EXIT_OK);
+        assert_eq!(exit_code(&[ok.clone(), partial.clone()]), EXIT_PARTIAL);
+        // Jobs left behind by the run deadline make the bundle incomplete.
+        let cancelled = JobOutcome::cancelled("loki app=ptaf-core", None, cancel::Cancelled::TimedOut);
+        assert_eq!(cancelled.error.as_deref(), Some("timed out"));
+        assert_eq!(exit_code(&[ok.clone(), skipped.clone(), cancelled]), EXIT_PARTIAL);
+
+        let table = table(&[ok, partial, skipped]);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("STATUS"));
+        assert!(lines[1].starts_with("partial  node-1  files /var/log/*"));
+        assert!(lines[1].ends_with("no such file"));
+        assert!(table.ends_with("jobs: 3, ok: 1, partial: 1, failed: 0, skipped: 1, cancelled: 0, bytes: 15, lines: 3\n"));
+    }
+}
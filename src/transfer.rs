@@ -0,0 +1,319 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use anyhow::{Result, bail, Context};
+use flate2::read::MultiGzDecoder;
+use sha2::{Digest, Sha256};
+
+use crate::cancel;
+use crate::config::{Compression, TransferConfig};
+use crate::progress;
+use crate::redact::Redactor;
+use crate::ssh_utils::{self, SSHConnection};
+
+/// Moves output of the node to local files, compressing it on the node when configured.
+pub struct Transfer<'a> {
+    config: &'a TransferConfig,
+    redactor: Option<&'a Redactor>,
+}
+
+impl<'a> Transfer<'a> {
+
+    pub fn new(config: &'a TransferConfig) -> Self {
+        Transfer { config, redactor: None }
+    }
+
+    /// Text written to local files is passed through the redactor.
+    /// Output kept compressed is not redacted.
+    pub fn with_redactor(mut self, redactor: Option<&'a Redactor>) -> Self {
+        self.redactor = redactor.filter(|_| !self.config.keep_compressed);
+        self
+    }
+
+    fn compressor(&self) -> Option<&'static str> {
+        match self.config.compression {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip -c"),
+            Compression::Zstd => Some("zstd -c -q"),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.config.compression {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Command with its stdout piped through the compressor. `pipefail` keeps the exit
+    /// status of the command where the shell supports it.
+    pub fn remote_command(&self, cmd: &str) -> String {
+        match self.compressor() {
+            Some(compressor) => format!("(set -o pipefail) 2>/dev/null && set -o pipefail; {} | {}", cmd, compressor),
+            None => cmd.to_string(),
+        }
+    }
+
+    /// Compressed output kept as is gets the extension of the compressor.
+    pub fn local_path(&self, dest_file: &str) -> String {
+        if self.config.keep_compressed {
+            format!("{}{}", dest_file, self.extension())
+        } else {
+            dest_file.to_string()
+        }
+    }
+
+    fn decoder<'r>(&self, reader: impl Read + 'r) -> Result<Box<dyn Read + 'r>> {
+        if self.config.keep_compressed {
+            return Ok(Box::new(progress::CountingReader::new(reader, false)));
+        }
+        let decoder: Box<dyn Read> = match self.config.compression {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        };
+        Ok(Box::new(progress::CountingReader::new(decoder, true)))
+    }
+
+    /// Streams stdout of the command into the local file. Returns the local path.
+    pub fn download(&self, conn: &SSHConnection, cmd: &str, dest_file: &str) -> Result<String> {
+        self.download_with(conn, cmd, "", dest_file, &[0])
+    }
+
+    /// Like `download`, `input` goes to stdin of the command.
+    pub fn download_with_input(&self, conn: &SSHConnection, cmd: &str, input: &str, dest_file: &str) -> Result<String> {
+        self.download_with(conn, cmd, input, dest_file, &[0])
+    }
+
+    fn download_with(&self, conn: &SSHConnection, cmd: &str, input: &str, dest_file: &str, ok_statuses: &[i32]) -> Result<String> {
+        let mut stream = conn.open_stream_with_input(&self.remote_command(cmd), input)?;
+        let dest_file = self.save(&mut stream, dest_file)?;
+        stream.finish(ok_statuses)?;
+        Ok(dest_file)
+    }
+
+    /// Copies a single remote file. Without compression SFTP is used. Either way the copy
+    /// goes to `<dest>.part` and is renamed when done and verified. Only SFTP copies are
+    /// resumed, a compressed one starts over, its offset is unknown after decompression.
+    pub fn download_file(&self, conn: &SSHConnection, source: &str, dest_file: &str) -> Result<String> {
+        let Some(compressor) = self.compressor() else {
+            if let Err(err) = conn.copy_to_local(source, dest_file, self.config.verify_checksum) {
+                // The copy goes to `.part`, which is kept as the partial output.
+                if cancel::is_cancelled() && fs::rename(format!("{}.part", dest_file), dest_file).is_ok() {
+                    return Err(cancel::mark_partial(dest_file));
+                }
+                return Err(err);
+            }
+            if let Some(redactor) = self.redactor {
+                redactor.redact_file(Path::new(dest_file))?;
+            }
+            return Ok(dest_file.to_string());
+        };
+        // Redirection instead of `cat |` keeps a failed read in the exit status.
+        let cmd = format!("{} < {}", compressor, ssh_utils::shell_quote(source));
+        let dest_file = self.local_path(dest_file);
+        let part_file = format!("{}.part", dest_file);
+        let mut stream = conn.open_stream(&cmd)?;
+        let mut reader = ChecksumReader::new(&mut stream, self.config.compression, self.config.verify_checksum)?;
+        if let Err(err) = self.write(&mut reader, &part_file) {
+            if cancel::is_cancelled() && fs::rename(&part_file, &dest_file).is_ok() {
+                return Err(cancel::mark_partial(&dest_file));
+            }
+            return Err(err);
+        }
+        let local = reader.finish();
+        let verified = stream.finish(&[0])
+            .with_context(|| format!("failed to read {}", source))
+            .and_then(|_| match local? {
+                Some(local) => match conn.sha256(source)? {
+                    remote if remote == local => Ok(()),
+                    remote => bail!("checksum mismatch of {}: remote {:?}, local {}", source, remote, local),
+                },
+                None => Ok(()),
+            });
+        if let Err(err) = verified {
+            fs::remove_file(&part_file)?;
+            return Err(err);
+        }
+        fs::rename(&part_file, &dest_file)?;
+        Ok(dest_file)
+    }
+
+    /// Copies a remote directory as a tar stream. It is unpacked to `dest_dir` or,
+    /// with `keep_compressed`, stored as `<dest_dir>.tar[.gz|.zst]`.
+    pub fn download_dir(&self, conn: &SSHConnection, source_dir: &str, dest_dir: &str) -> Result<String> {
+        let source = Path::new(source_dir.trim_end_matches('/'));
+        let (Some(parent), Some(name)) = (source.parent(), source.file_name()) else {
+            bail!("can't archive directory: {}", source_dir);
+        };
+        let cmd = format!(
+            "tar -C {} -cf - {}",
+            ssh_utils::shell_quote(&parent.to_string_lossy()),
+            ssh_utils::shell_quote(&name.to_string_lossy()),
+        );
+        // tar exits with 1 when a file changed while it was read, e.g. a growing log.
+        let ok_statuses = [0, 1];
+        if self.config.keep_compressed {
+            let dest_file = format!("{}.tar", dest_dir.trim_end_matches('/'));
+            return self.download_with(conn, &cmd, "", &dest_file, &ok_statuses);
+        }
+
+        // The archive has the directory itself at the top, so it is unpacked one level up.
+        let dest_parent = Path::new(dest_dir.trim_end_matches('/'))
+            .parent()
+            .unwrap_or(Path::new("."));
+        fs::create_dir_all(dest_parent)?;
+        let mut stream = conn.open_stream(&self.remote_command(&cmd))?;
+        let unpacked = tar::Archive::new(self.decoder(&mut stream)?).unpack(dest_parent);
+        if unpacked.is_err() && cancel::is_cancelled() {
+            return Err(cancel::mark_partial(dest_dir));
+        }
+        unpacked?;
+        stream.finish(&ok_statuses)?;
+        if let Some(redactor) = self.redactor {
+            redact_dir(redactor, Path::new(dest_dir))?;
+        }
+        Ok(dest_dir.to_string())
+    }
+
+    fn save(&self, reader: impl Read, dest_file: &str) -> Result<String> {
+        let dest_file = self.local_path(dest_file);
+        match self.write(reader, &dest_file) {
+            Err(_) if cancel::is_cancelled() => Err(cancel::mark_partial(&dest_file)),
+            written => written.map(|_| dest_file),
+        }
+    }
+
+    fn write(&self, reader: impl Read, path: &str) -> Result<()> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::File::create(path)?;
+        match self.redactor {
+            Some(redactor) => {
+                let mut writer = redactor.writer(file);
+                io::copy(&mut self.decoder(reader)?, &mut writer)?;
+                writer.finish()?;
+            },
+            None => {
+                io::copy(&mut self.decoder(reader)?, &mut file)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Passes compressed output through and hashes it decompressed, so the copy can be
+/// compared with `sha256sum` of the source, kept compressed, redacted or not.
+struct ChecksumReader<R> {
+    inner: R,
+    decompressed: Option<Decompressed>,
+}
+
+enum Decompressed {
+    Gzip(flate2::write::MultiGzDecoder<Sha256>),
+    Zstd(zstd::stream::write::Decoder<'static, Sha256>),
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R, compression: Compression, verify: bool) -> Result<Self> {
+        let decompressed = match compression {
+            _ if !verify => None,
+            Compression::None => None,
+            Compression::Gzip => Some(Decompressed::Gzip(flate2::write::MultiGzDecoder::new(Sha256::new()))),
+            Compression::Zstd => Some(Decompressed::Zstd(zstd::stream::write::Decoder::new(Sha256::new())?)),
+        };
+        Ok(ChecksumReader { inner, decompressed })
+    }
+
+    /// Hex encoded SHA-256 of the decompressed output, `None` when it isn't verified.
+    fn finish(self) -> Result<Option<String>> {
+        let hasher = match self.decompressed {
+            None => return Ok(None),
+            Some(Decompressed::Gzip(decoder)) => decoder.finish()?,
+            Some(Decompressed::Zstd(mut decoder)) => {
+                decoder.flush()?;
+                decoder.into_inner()
+            },
+        };
+        Ok(Some(hex::encode(hasher.finalize())))
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        match &mut self.decompressed {
+            Some(Decompressed::Gzip(decoder)) => decoder.write_all(&buf[..bytes_read])?,
+            Some(Decompressed::Zstd(decoder)) => decoder.write_all(&buf[..bytes_read])?,
+            None => {},
+        }
+        Ok(bytes_read)
+    }
+}
+
+fn redact_dir(redactor: &Redactor, dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_symlink() {
+            continue;
+        }
+        if path.is_dir() {
+            redact_dir(redactor, &path)?;
+        } else {
+            redactor.redact_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_command_and_decoder() {
+        let config = TransferConfig {
+            compression: Compression::Gzip,
+            keep_compressed: false,
+            tar_directories: true,
+            verify_checksum: true,
+        };
+        let transfer = Transfer::new(&config);
+        assert_eq!(
+            transfer.remote_command("journalctl -u kubelet"),
+            "(set -o pipefail) 2>/dev/null && set -o pipefail; journalctl -u kubelet | gzip -c",
+        );
+        assert_eq!(transfer.local_path("/tmp/kubelet.log"), "/tmp/kubelet.log");
+
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"line 1\nline 2\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decoded = String::new();
+        transfer.decoder(compressed.as_slice()).unwrap().read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "line 1\nline 2\n");
+    }
+
+    #[test]
+    fn test_checksum_reader() {
+        // sha256 of "line 1\nline 2\n", as `sha256sum` of the source prints it.
+        let expected = hex::encode(Sha256::digest(b"line 1\nline 2\n"));
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"line 1\nline 2\n").unwrap();
+        let gzip = encoder.finish().unwrap();
+        let zstd = zstd::encode_all(&b"line 1\nline 2\n"[..], 0).unwrap();
+
+        for (compression, compressed) in [(Compression::Gzip, gzip), (Compression::Zstd, zstd)] {
+            let mut reader = ChecksumReader::new(compressed.as_slice(), compression, true).unwrap();
+            let mut passed = vec![];
+            reader.read_to_end(&mut passed).unwrap();
+            assert_eq!(passed, compressed);
+            assert_eq!(reader.finish().unwrap(), Some(expected.clone()));
+        }
+        let reader = ChecksumReader::new(&b""[..], Compression::Gzip, false).unwrap();
+        assert_eq!(reader.finish().unwrap(), None);
+    }
+}
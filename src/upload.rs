@@ -0,0 +1,401 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use anyhow::{Result, bail, Context};
+use chrono::{DateTime, Utc};
+use curl::easy::{Easy, List, ReadError};
+use hmac::{Hmac, Mac};
+use r2d2::ManageConnection;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::config::{self, HttpMethod, UploadTarget};
+use crate::retry::{self, HttpStatusError};
+use crate::session_manager;
+use crate::ssh_utils;
+
+const MIN_PART_SIZE_MB: u64 = 5;
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Where the bundle goes, written to the manifest before packing.
+#[derive(Clone, Debug, Serialize)]
+pub struct UploadRecord {
+    pub target: UploadTarget,
+    pub url: String,
+    /// Object key for S3.
+    pub key: Option<String>,
+}
+
+struct Response {
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+fn request(method: &str, url: &str, headers: &[String], body: Option<(&mut dyn Read, u64)>) -> Result<Response> {
+    let mut handle = Easy::new();
+    handle.url(url)?;
+    let mut list = List::new();
+    for header in headers {
+        list.append(header)?;
+    }
+    // Otherwise curl waits for `100 Continue` before sending a large body.
+    list.append("Expect:")?;
+    handle.http_headers(list)?;
+    let size = body.as_ref().map_or(0, |(_, size)| *size);
+    match method {
+        "PUT" => {
+            handle.upload(true)?;
+            handle.in_filesize(size)?;
+        },
+        "POST" => {
+            handle.post(true)?;
+            handle.post_field_size(size)?;
+        },
+        _ => handle.custom_request(method)?,
+    }
+
+    let mut headers = vec![];
+    let mut data = vec![];
+    {
+        let mut transfer = handle.transfer();
+        if let Some((reader, _)) = body {
+            transfer.read_function(move |buf| reader.read(buf).map_err(|_| ReadError::Abort))?;
+        }
+        transfer.header_function(|line| {
+            headers.push(String::from_utf8_lossy(line).trim_end().to_string());
+            true
+        })?;
+        transfer.write_function(|chunk| {
+            data.extend_from_slice(chunk);
+            Ok(chunk.len())
+        })?;
+        transfer.perform()?;
+    }
+    let code = handle.response_code()?;
+    if code >= 300 {
+        return Err(HttpStatusError { code, body: String::from_utf8_lossy(&data).to_string() }.into());
+    }
+    Ok(Response { headers, body: data })
+}
+
+/// Sends the packed bundle to the configured target.
+pub struct Uploader<'a> {
+    config: &'a config::UploadConfig,
+    retry_policy: &'a config::RetryPolicy,
+}
+
+impl<'a> Uploader<'a> {
+
+    pub fn new(config: &'a config::UploadConfig, retry_policy: &'a config::RetryPolicy) -> Option<Self> {
+        (config.target != UploadTarget::None).then_some(Uploader { config, retry_policy })
+    }
+
+    pub fn record(&self, file_name: &str) -> UploadRecord {
+        let target = self.config.target;
+        match target {
+            UploadTarget::S3 => {
+                let key = format!("{}{}", self.config.s3.prefix.as_deref().unwrap_or_default(), file_name);
+                let url = S3Client { config: &self.config.s3 }.url(&key, &[]);
+                UploadRecord { target, url, key: Some(key) }
+            },
+            UploadTarget::Http => {
+                let url = &self.config.http.url;
+                let url = if url.ends_with('/') { format!("{}{}", url, file_name) } else { url.clone() };
+                UploadRecord { target, url, key: None }
+            },
+            UploadTarget::Sftp => {
+                let sftp = &self.config.sftp;
+                let url = format!("sftp://{}@{}:{}{}", sftp.login, sftp.host, sftp.port, self.sftp_path(file_name));
+                UploadRecord { target, url, key: None }
+            },
+            UploadTarget::None => UploadRecord { target, url: String::new(), key: None },
+        }
+    }
+
+    pub fn upload(&self, bundle_path: &str) -> Result<UploadRecord> {
+        let path = Path::new(bundle_path);
+        let file_name = path
+            .file_name()
+            .with_context(|| format!("bad bundle path: {}", bundle_path))?
+            .to_string_lossy()
+            .to_string();
+        let record = self.record(&file_name);
+        match self.config.target {
+            UploadTarget::S3 => self.upload_s3(path, record.key.as_deref().unwrap_or_default())?,
+            UploadTarget::Http => self.upload_http(path, &record.url)?,
+            UploadTarget::Sftp => self.upload_sftp(path, &self.sftp_path(&file_name))?,
+            UploadTarget::None => {},
+        }
+        Ok(record)
+    }
+
+    fn with_retries<T>(&self, what: &str, f: impl Fn() -> Result<T>) -> Result<T> {
+        let (result, _) = retry::run(self.retry_policy, what, f);
+        result.with_context(|| format!("{} failed", what))
+    }
+
+    fn upload_http(&self, path: &Path, url: &str) -> Result<()> {
+        let http = &self.config.http;
+        let mut headers = vec!["Content-Type: application/octet-stream".to_string()];
+        if let Some(auth_header) = &http.auth_header {
+            headers.push(format!("Authorization: {}", auth_header));
+        }
+        let method = match http.method {
+            HttpMethod::Put => "PUT",
+            HttpMethod::Post => "POST",
+        };
+        let size = fs::metadata(path)?.len();
+        self.with_retries(&format!("upload to {}", url), || {
+            let mut file = fs::File::open(path)?;
+            request(method, url, &headers, Some((&mut file, size)))?;
+            Ok(())
+        })
+    }
+
+    /// Bundles up to the part size go in a single request, larger ones as a multipart
+    /// upload which is aborted on failure so no parts are left in the bucket.
+    fn upload_s3(&self, path: &Path, key: &str) -> Result<()> {
+        let s3 = S3Client { config: &self.config.s3 };
+        let size = fs::metadata(path)?.len();
+        let part_size = self.config.s3.part_size_mb.max(MIN_PART_SIZE_MB) * 1024 * 1024;
+        if size <= part_size {
+            let payload_hash = ssh_utils::sha256_file(&path.to_string_lossy())?;
+            return self.with_retries(&format!("upload {}", key), || {
+                let mut file = fs::File::open(path)?;
+                s3.send("PUT", key, &[], &payload_hash, Some((&mut file, size)))?;
+                Ok(())
+            });
+        }
+
+        let response = self.with_retries(&format!("start multipart upload of {}", key), || {
+            s3.send("POST", key, &[("uploads", "")], EMPTY_SHA256, None)
+        })?;
+        let upload_id = xml_value(&response.body, "UploadId").context("no UploadId in response")?;
+        let result = self.upload_parts(&s3, path, key, &upload_id, part_size);
+        if result.is_err() {
+            if let Err(err) = s3.send("DELETE", key, &[("uploadId", &upload_id)], EMPTY_SHA256, None) {
+                warn!(upload_id, "failed to abort upload: {:#}", err);
+            }
+        }
+        result
+    }
+
+    fn upload_parts(&self, s3: &S3Client, path: &Path, key: &str, upload_id: &str, part_size: u64) -> Result<()> {
+        let mut file = fs::File::open(path)?;
+        let mut parts = vec![];
+        for part_number in 1.. {
+            let mut part = vec![];
+            (&mut file).take(part_size).read_to_end(&mut part)?;
+            if part.is_empty() {
+                break;
+            }
+            let payload_hash = hex::encode(Sha256::digest(&part));
+            let number = part_number.to_string();
+            let _span = tracing::info_span!("chunk", part = part_number, size = part.len()).entered();
+            let response = self.with_retries(&format!("upload part {} of {}", number, key), || {
+                let mut reader = part.as_slice();
+                let query = [("partNumber", number.as_str()), ("uploadId", upload_id)];
+                s3.send("PUT", key, &query, &payload_hash, Some((&mut reader, part.len() as u64)))
+            })?;
+            let etag = response.header("ETag").context("no ETag in part response")?;
+            parts.push(format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", number, etag));
+            debug!(key, "part uploaded");
+        }
+
+        let body = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts.concat());
+        let payload_hash = hex::encode(Sha256::digest(body.as_bytes()));
+        let response = self.with_retries(&format!("complete upload of {}", key), || {
+            let mut reader = body.as_bytes();
+            s3.send("POST", key, &[("uploadId", upload_id)], &payload_hash, Some((&mut reader, body.len() as u64)))
+        })?;
+        // Completion may fail after `200 OK` was already sent.
+        if xml_value(&response.body, "Code").is_some() {
+            bail!("failed to complete upload of {}: {}", key, String::from_utf8_lossy(&response.body));
+        }
+        Ok(())
+    }
+
+    fn sftp_path(&self, file_name: &str) -> String {
+        format!("{}/{}", self.config.sftp.dir.trim_end_matches('/'), file_name)
+    }
+
+    /// The file is renamed once complete, so the drop server never sees a partial bundle.
+    fn upload_sftp(&self, path: &Path, remote_path: &str) -> Result<()> {
+        let sftp_config = &self.config.sftp;
+        let manager = session_manager::SessionManager {
+            host: sftp_config.host.clone(),
+            port: sftp_config.port.to_string(),
+            login: sftp_config.login.clone(),
+            password: sftp_config.password.clone(),
+            key_file: sftp_config.key_file.clone(),
+            timeouts: session_manager::SessionTimeouts::default(),
+        };
+        self.with_retries(&format!("upload to {}", remote_path), || {
+            let session = manager.connect()?;
+            let sftp = session.sftp()?;
+            let tmp_path = format!("{}.part", remote_path);
+            let mut remote = sftp.create(Path::new(&tmp_path))?;
+            io::copy(&mut fs::File::open(path)?, &mut remote)?;
+            drop(remote);
+            sftp.rename(Path::new(&tmp_path), Path::new(remote_path), None)?;
+            Ok(())
+        })
+    }
+}
+
+/// Path style requests signed with AWS Signature Version 4.
+struct S3Client<'a> {
+    config: &'a config::S3Config,
+}
+
+impl S3Client<'_> {
+
+    fn host(&self) -> &str {
+        let endpoint = self.config.endpoint.as_str();
+        let endpoint = endpoint.split_once("://").map_or(endpoint, |(_, rest)| rest);
+        endpoint.split('/').next().unwrap_or(endpoint)
+    }
+
+    fn canonical_uri(&self, key: &str) -> String {
+        format!("/{}/{}", uri_encode(&self.config.bucket, false), uri_encode(key, true))
+    }
+
+    fn url(&self, key: &str, query: &[(&str, &str)]) -> String {
+        let mut url = format!("{}{}", self.config.endpoint.trim_end_matches('/'), self.canonical_uri(key));
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&canonical_query(query));
+        }
+        url
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        payload_hash: &str,
+        body: Option<(&mut dyn Read, u64)>,
+    ) -> Result<Response> {
+        let headers = self.sign(method, &self.canonical_uri(key), &canonical_query(query), payload_hash, Utc::now());
+        request(method, &self.url(key, query), &headers, body)
+    }
+
+    fn sign(&self, method: &str, uri: &str, query: &str, payload_hash: &str, now: DateTime<Utc>) -> Vec<String> {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let canonical_headers = format!(
+            "host:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n",
+            self.host(), payload_hash, amz_date,
+        );
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, uri, query, canonical_headers, SIGNED_HEADERS, payload_hash,
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+        let key = signing_key(&self.config.secret_key, &date, &self.config.region, "s3");
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+        vec![
+            format!("x-amz-content-sha256: {}", payload_hash),
+            format!("x-amz-date: {}", amz_date),
+            format!(
+                "Authorization: AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.config.access_key, scope, SIGNED_HEADERS, signature,
+            ),
+        ]
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes a key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut result = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => result.push(byte as char),
+            b'/' if keep_slash => result.push('/'),
+            _ => result.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    result
+}
+
+fn canonical_query(query: &[(&str, &str)]) -> String {
+    let mut params = query
+        .iter()
+        .map(|(key, value)| format!("{}={}", uri_encode(key, false), uri_encode(value, false)))
+        .collect::<Vec<_>>();
+    params.sort();
+    params.join("&")
+}
+
+fn xml_value(xml: &[u8], tag: &str) -> Option<String> {
+    let xml = String::from_utf8_lossy(xml);
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..end].to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS Signature Version 4 documentation.
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex::encode(key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+        assert_eq!(hex::encode(Sha256::digest(b"")), EMPTY_SHA256);
+    }
+
+    #[test]
+    fn test_s3_record() {
+        let mut cfg = config::Config::from_string(constants::DEFAULT_CONFIG).unwrap();
+        assert!(Uploader::new(&cfg.param.upload, cfg.param.retry.for_upload()).is_none());
+
+        cfg.param.upload.target = UploadTarget::S3;
+        cfg.param.upload.s3.endpoint = "http://localhost:9000/".to_string();
+        cfg.param.upload.s3.bucket = "support".to_string();
+        let uploader = Uploader::new(&cfg.param.upload, cfg.param.retry.for_upload()).unwrap();
+        let record = uploader.record("harvester 1.tar.gz");
+        assert_eq!(record.key.as_deref(), Some("harvester/harvester 1.tar.gz"));
+        assert_eq!(record.url, "http://localhost:9000/support/harvester/harvester%201.tar.gz");
+        assert_eq!(S3Client { config: &cfg.param.upload.s3 }.host(), "localhost:9000");
+        assert_eq!(canonical_query(&[("uploadId", "a/b"), ("partNumber", "1")]), "partNumber=1&uploadId=a%2Fb");
+    }
+
+    #[test]
+    fn test_xml_value() {
+        let xml = b"<InitiateMultipartUploadResult><Key>a</Key><UploadId>abc-1</UploadId></InitiateMultipartUploadResult>";
+        assert_eq!(xml_value(xml, "UploadId").as_deref(), Some("abc-1"));
+        assert_eq!(xml_value(xml, "Code"), None);
+    }
+}
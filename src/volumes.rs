@@ -0,0 +1,180 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use anyhow::{Result, bail, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const MANIFEST_SUFFIX: &str = ".volumes.json";
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Volume {
+    pub file: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Written next to the volumes as `<bundle>.volumes.json`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VolumeManifest {
+    pub bundle: String,
+    pub size: u64,
+    pub sha256: String,
+    pub volumes: Vec<Volume>,
+}
+
+/// `<bundle>.volumes.json`, the manifest `split` writes next to the volumes.
+pub fn manifest_path(bundle_path: &str) -> String {
+    format!("{}{}", bundle_path, MANIFEST_SUFFIX)
+}
+
+fn volume_path(bundle_path: &str, number: usize) -> String {
+    format!("{}.{:03}", bundle_path, number)
+}
+
+/// Splits the bundle into `<bundle>.001`, `<bundle>.002`, ... of at most `volume_size`
+/// bytes and removes it. Returns the volumes followed by the volume manifest.
+pub fn split(bundle_path: &str, volume_size: u64) -> Result<Vec<String>> {
+    let mut bundle = fs::File::open(bundle_path)?;
+    let mut bundle_hasher = Sha256::new();
+    let mut volumes = vec![];
+    let mut files = vec![];
+    loop {
+        let mut chunk = (&mut bundle).take(volume_size);
+        let path = volume_path(bundle_path, volumes.len() + 1);
+        let mut volume = fs::File::create(&path)?;
+        let mut hasher = Sha256::new();
+        let size = copy_hashed(&mut chunk, &mut volume, &mut [&mut hasher, &mut bundle_hasher])?;
+        if size == 0 {
+            drop(volume);
+            fs::remove_file(&path)?;
+            break;
+        }
+        volumes.push(Volume { file: file_name(&path), size, sha256: hex::encode(hasher.finalize()) });
+        files.push(path);
+    }
+
+    let manifest = VolumeManifest {
+        bundle: file_name(bundle_path),
+        size: volumes.iter().map(|x| x.size).sum(),
+        sha256: hex::encode(bundle_hasher.finalize()),
+        volumes,
+    };
+    let manifest_path = manifest_path(bundle_path);
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+    files.push(manifest_path);
+    fs::remove_file(bundle_path)?;
+    Ok(files)
+}
+
+/// Reassembles the bundle from its volumes, checking every volume against the volume
+/// manifest when it is present. Accepts the bundle path, any volume or the manifest.
+pub fn join(path: &str, output: Option<&str>) -> Result<String> {
+    let bundle_path = bundle_path(path);
+    let output = output.unwrap_or(&bundle_path).to_string();
+    let manifest_path = manifest_path(&bundle_path);
+    let manifest: Option<VolumeManifest> = match fs::read_to_string(&manifest_path) {
+        Ok(text) => Some(serde_json::from_str(&text).with_context(|| format!("bad volume manifest {}", manifest_path))?),
+        Err(_) => None,
+    };
+    let dir = Path::new(&bundle_path).parent().unwrap_or(Path::new(""));
+    let volumes = match &manifest {
+        Some(manifest) => manifest.volumes.iter().map(|x| dir.join(&x.file).to_string_lossy().to_string()).collect(),
+        None => (1..).map(|x| volume_path(&bundle_path, x)).take_while(|x| Path::new(x).exists()).collect::<Vec<_>>(),
+    };
+    if volumes.is_empty() {
+        bail!("no volumes found for {}", bundle_path);
+    }
+
+    let tmp_output = format!("{}.part", output);
+    let result = join_volumes(&volumes, manifest.as_ref(), &tmp_output);
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_output);
+    }
+    result?;
+    fs::rename(&tmp_output, &output)?;
+    Ok(output)
+}
+
+fn join_volumes(volumes: &[String], manifest: Option<&VolumeManifest>, output: &str) -> Result<()> {
+    let mut output = fs::File::create(output)?;
+    let mut bundle_hasher = Sha256::new();
+    for (index, path) in volumes.iter().enumerate() {
+        let mut volume = fs::File::open(path).with_context(|| format!("missing volume {}", path))?;
+        let mut hasher = Sha256::new();
+        copy_hashed(&mut volume, &mut output, &mut [&mut hasher, &mut bundle_hasher])?;
+        if let Some(expected) = manifest.map(|x| &x.volumes[index].sha256) {
+            if &hex::encode(hasher.finalize()) != expected {
+                bail!("checksum mismatch for volume {}", path);
+            }
+        }
+    }
+    if let Some(manifest) = manifest {
+        if hex::encode(bundle_hasher.finalize()) != manifest.sha256 {
+            bail!("checksum mismatch for bundle {}", manifest.bundle);
+        }
+    }
+    Ok(())
+}
+
+fn copy_hashed(reader: &mut impl Read, writer: &mut impl Write, hashers: &mut [&mut Sha256]) -> Result<u64> {
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let size = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(size) => size,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        writer.write_all(&buf[..size])?;
+        hashers.iter_mut().for_each(|x| x.update(&buf[..size]));
+        total += size as u64;
+    }
+    Ok(total)
+}
+
+fn bundle_path(path: &str) -> String {
+    if let Some(bundle) = path.strip_suffix(MANIFEST_SUFFIX) {
+        return bundle.to_string();
+    }
+    match path.rsplit_once('.') {
+        Some((bundle, number)) if number.len() == 3 && number.bytes().all(|x| x.is_ascii_digit()) => bundle.to_string(),
+        _ => path.to_string(),
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map_or(path.to_string(), |x| x.to_string_lossy().to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_join() {
+        let dir = std::env::temp_dir().join("harvester_test_volumes");
+        fs::create_dir_all(&dir).unwrap();
+        let bundle = dir.join("bundle.tar.gz").to_string_lossy().to_string();
+        let data = (0..2500u32).map(|x| x as u8).collect::<Vec<_>>();
+        fs::write(&bundle, &data).unwrap();
+
+        let files = split(&bundle, 1000).unwrap();
+        assert_eq!(files.len(), 4);
+        // The upload record of a split bundle names the volume manifest.
+        assert_eq!(files[3], manifest_path(&bundle));
+        assert!(!Path::new(&bundle).exists());
+        assert_eq!(fs::metadata(volume_path(&bundle, 3)).unwrap().len(), 500);
+
+        let output = join(&volume_path(&bundle, 2), None).unwrap();
+        assert_eq!(output, bundle);
+        assert_eq!(fs::read(&bundle).unwrap(), data);
+
+        fs::write(volume_path(&bundle, 2), b"damaged").unwrap();
+        assert!(join(&files[3], None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}